@@ -1,291 +1,837 @@
+use fake_agent::FakeAgentManager;
+use fake_characteristic::FakeBluetoothGATTCharacteristic;
+use fake_descriptor::FakeBluetoothGATTDescriptor;
 use fake_device::FakeBluetoothDevice;
-use fake_discovery_session::FakeBluetoothDiscoverySession;
-use std::error::Error;
-use std::sync::Arc;
-use rustc_serialize::hex::FromHex;
+use fake_discovery_session::{FakeBluetoothDiscoverySession, FakeDiscoveryFilter};
+use fake_error::{FakeBluetoothBuildError, FakeBluetoothError};
+use fake_event::{FakeBluetoothEvent, FakePropertyValue};
+use fake_service::FakeBluetoothGATTService;
+use modalias::Modalias;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Instant;
 
 #[derive(Clone, Debug)]
-pub struct FakeBluetoothAdapter {
-    object_path: String,
-    is_present: bool,
-    is_powered: bool,
-    can_start_discovery: bool,
-    can_stop_discovery: bool,
-    devices: Vec<Arc<FakeBluetoothDevice>>,
-    addatas: Vec<String>,
-    address: String,
-    name: String,
-    alias: String,
-    class: u32,
-    is_discoverable: bool,
-    is_pairable: bool,
-    pairable_timeout: u32,
-    discoverable_timeout: u32,
-    is_discovering: bool,
-    uuids: Vec<String>,
-    modalias: String,
+pub(crate) enum FakeBluetoothObject {
+    Device(Arc<FakeBluetoothDevice>),
+    Service(Arc<FakeBluetoothGATTService>),
+    Characteristic(Arc<FakeBluetoothGATTCharacteristic>),
+    Descriptor(Arc<FakeBluetoothGATTDescriptor>),
 }
 
-impl FakeBluetoothAdapter {
-    pub fn new(object_path: String,
-               is_present: bool,
-               is_powered: bool,
-               can_start_discovery: bool,
-               can_stop_discovery: bool,
-               devices: Vec<Arc<FakeBluetoothDevice>>,
-               addatas: Vec<String>,
-               address: String,
-               name: String,
-               alias: String,
-               class: u32,
-               is_discoverable: bool,
-               is_pairable: bool,
-               pairable_timeout: u32,
-               discoverable_timeout: u32,
-               is_discovering: bool,
-               uuids: Vec<String>,
-               modalias: String)
-               ->FakeBluetoothAdapter{
-        FakeBluetoothAdapter{
-            object_path: object_path,
-            is_present: is_present,
-            is_powered: is_powered,
-            can_start_discovery: can_start_discovery,
-            can_stop_discovery: can_stop_discovery,
-            devices: devices,
-            addatas: addatas,
-            address: address,
-            name: name,
-            alias: alias,
-            class: class,
-            is_discoverable: is_discoverable,
-            is_pairable: is_pairable,
-            pairable_timeout: pairable_timeout,
-            discoverable_timeout: discoverable_timeout,
-            is_discovering: is_discovering,
-            uuids: uuids,
-            modalias: modalias,
-        }
+impl FakeBluetoothObject {
+    // GATT objects only show up once their device exposes its services.
+    fn is_visible(&self) -> bool {
+        let device = match *self {
+            FakeBluetoothObject::Device(ref device) => return device.is_discovered(),
+            FakeBluetoothObject::Service(ref service) => service.get_device(),
+            FakeBluetoothObject::Characteristic(ref characteristic) => characteristic.get_device(),
+            FakeBluetoothObject::Descriptor(ref descriptor) => {
+                descriptor.get_characteristic().and_then(|characteristic| characteristic.get_device())
+            },
+        };
+        device.map(|device| device.is_gatt_visible()).unwrap_or(false)
     }
+}
+
+#[derive(Debug)]
+pub struct FakeBluetoothAdapter {
+    object_path: Mutex<String>,
+    is_present: Mutex<bool>,
+    is_powered: Mutex<bool>,
+    can_start_discovery: Mutex<bool>,
+    can_stop_discovery: Mutex<bool>,
+    devices: Mutex<Vec<Arc<FakeBluetoothDevice>>>,
+    addatas: Mutex<Vec<String>>,
+    address: Mutex<String>,
+    name: Mutex<String>,
+    alias: Mutex<String>,
+    class: Mutex<u32>,
+    is_discoverable: Mutex<bool>,
+    is_pairable: Mutex<bool>,
+    powered_off_modes: Mutex<Option<(bool, bool)>>,
+    pairable_timeout: Mutex<u32>,
+    discoverable_timeout: Mutex<u32>,
+    is_discovering: Mutex<bool>,
+    discovery_sessions: Mutex<HashMap<usize, Option<FakeDiscoveryFilter>>>,
+    discovery_started: Mutex<Option<Instant>>,
+    discovery_polls: Mutex<u32>,
+    uuids: Mutex<Vec<String>>,
+    modalias: Mutex<String>,
+    objects: Mutex<HashMap<String, FakeBluetoothObject>>,
+    agent_manager: Arc<FakeAgentManager>,
+    subscribers: Mutex<Vec<Sender<FakeBluetoothEvent>>>,
+}
 
+impl FakeBluetoothAdapter {
     pub fn new_empty() -> FakeBluetoothAdapter {
         FakeBluetoothAdapter {
-            object_path: String::new(),
-            is_present: false,
-            is_powered: false,
-            can_start_discovery: false,
-            can_stop_discovery: false,
-            devices: vec![],
-            addatas: vec![],
-            address: String::new(),
-            name: String::new(),
-            alias: String::new(),
-            class: 0,
-            is_discoverable: false,
-            is_pairable: false,
-            pairable_timeout: 0,
-            discoverable_timeout: 0,
-            is_discovering: false,
-            uuids: vec![],
-            modalias: String::new(),
+            object_path: Mutex::new(String::new()),
+            is_present: Mutex::new(true),
+            is_powered: Mutex::new(false),
+            can_start_discovery: Mutex::new(false),
+            can_stop_discovery: Mutex::new(false),
+            devices: Mutex::new(vec![]),
+            addatas: Mutex::new(vec![]),
+            address: Mutex::new(String::new()),
+            name: Mutex::new(String::new()),
+            alias: Mutex::new(String::new()),
+            class: Mutex::new(0),
+            is_discoverable: Mutex::new(false),
+            is_pairable: Mutex::new(false),
+            powered_off_modes: Mutex::new(None),
+            pairable_timeout: Mutex::new(0),
+            discoverable_timeout: Mutex::new(0),
+            is_discovering: Mutex::new(false),
+            discovery_sessions: Mutex::new(HashMap::new()),
+            discovery_started: Mutex::new(None),
+            discovery_polls: Mutex::new(0),
+            uuids: Mutex::new(vec![]),
+            modalias: Mutex::new(String::new()),
+            objects: Mutex::new(HashMap::new()),
+            agent_manager: Arc::new(FakeAgentManager::new()),
+            subscribers: Mutex::new(vec![]),
         }
     }
 
     pub fn get_id(&self) -> String {
-        self.object_path.clone()
+        ::lock(&self.object_path).clone()
     }
 
-    pub fn set_id(&mut self, value: String) {
-        self.object_path = value;
+    make_setter!(set_id, object_path, String);
+
+    // Works while the adapter is missing, unlike everything else.
+    pub fn is_present(&self) -> Result<bool, FakeBluetoothError> {
+        Ok(*::lock(&self.is_present))
     }
 
-    pub fn is_present(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_present)
+    // An absent adapter behaves like an unplugged controller: it is powered
+    // off on the way out and every call on it fails until it is back.
+    pub fn set_present(&self, present: bool) -> Result<(), FakeBluetoothError> {
+        if !present && *::lock(&self.is_present) {
+            self.set_powered(false)?;
+        }
+        *::lock(&self.is_present) = present;
+        Ok(())
     }
 
-    pub fn set_present(&mut self, value: bool) {
-        self.is_present = value;
+    make_getter!(is_powered, is_powered, bool);
+
+    // Powering off drops every connection and discovery session and turns
+    // discoverable and pairable off; powering on turns them back on.
+    pub fn set_powered(&self, powered: bool) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if ::update(&self.is_powered, powered).is_none() {
+            return Ok(());
+        }
+        self.property_changed("Powered", (!powered).into(), powered.into());
+        if powered {
+            if let Some((discoverable, pairable)) = ::lock(&self.powered_off_modes).take() {
+                self.update_mode(&self.is_discoverable, "Discoverable", discoverable);
+                self.update_mode(&self.is_pairable, "Pairable", pairable);
+            }
+            return Ok(());
+        }
+        for device in ::lock(&self.devices).iter() {
+            device.drop_connection();
+        }
+        let was_discovering = {
+            let mut sessions = ::lock(&self.discovery_sessions);
+            let was_discovering = !sessions.is_empty();
+            sessions.clear();
+            was_discovering
+        };
+        if was_discovering {
+            self.end_discovery();
+        }
+        *::lock(&self.powered_off_modes) = Some((*::lock(&self.is_discoverable), *::lock(&self.is_pairable)));
+        self.update_mode(&self.is_discoverable, "Discoverable", false);
+        self.update_mode(&self.is_pairable, "Pairable", false);
+        Ok(())
     }
 
-    pub fn is_powered(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_powered)
+    fn update_mode(&self, mode: &Mutex<bool>, property: &str, value: bool) {
+        if let Some(old_value) = ::update(mode, value) {
+            self.property_changed(property, old_value.into(), value.into());
+        }
     }
 
-    pub fn set_powered(&mut self, value: bool) -> Result<(), Box<Error>> {
-        self.is_powered = value;
+    pub(crate) fn check_powered(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if !*::lock(&self.is_powered) {
+            return Err(FakeBluetoothError::NotPowered);
+        }
         Ok(())
     }
 
-    pub fn get_can_start_discovery(&self) -> Result<bool, Box<Error>> {
-        Ok(self.can_start_discovery)
+    make_getter!(get_can_start_discovery, can_start_discovery, bool);
+
+    make_setter!(set_can_start_discovery, can_start_discovery, bool);
+
+    make_getter!(get_can_stop_discovery, can_stop_discovery, bool);
+
+    make_setter!(set_can_stop_discovery, can_stop_discovery, bool);
+
+    // Every call while discovering counts as one poll towards the devices
+    // scheduled to show up after a number of polls.
+    pub fn get_device_list(&self) -> Result<Vec<Arc<FakeBluetoothDevice>>, FakeBluetoothError> {
+        self.check_valid()?;
+        if ::lock(&self.discovery_started).is_some() {
+            *::lock(&self.discovery_polls) += 1;
+        }
+        self.update_discovered_devices();
+        Ok(::lock(&self.devices).iter().filter(|device| device.is_discovered()).cloned().collect())
+    }
+
+    // Devices missing from the new list are removed as by remove_device()
+    // and new ones are added as by add_device(); the rest stay untouched.
+    // Nothing changes unless every new device can be added.
+    pub fn set_devices(self: &Arc<Self>, devices: Vec<Arc<FakeBluetoothDevice>>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let (removed, added) = ::diff_children(&::lock(&self.devices), &devices)?;
+        let mut ids: Vec<String> = devices.iter()
+                                          .filter(|device| !added.iter().any(|new| Arc::ptr_eq(new, device)))
+                                          .map(|device| device.get_id())
+                                          .collect();
+        for device in &added {
+            device.check_unattached()?;
+            let id = self.device_path(device)?;
+            if ids.contains(&id) {
+                return Err(FakeBluetoothError::AlreadyExists(format!("Device {} already exists.", device.get_address()?)));
+            }
+            ids.push(id);
+        }
+        for device in removed {
+            self.remove_device(device.get_id())?;
+        }
+        for device in added {
+            self.add_device(device)?;
+        }
+        *::lock(&self.devices) = devices;
+        Ok(())
     }
 
-    pub fn set_can_start_discovery(&mut self, value: bool) {
-        self.can_start_discovery = value;
+    pub fn add_device(self: &Arc<Self>, device: Arc<FakeBluetoothDevice>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        device.check_unattached()?;
+        let id = self.device_path(&device)?;
+        if ::lock(&self.objects).contains_key(&id) {
+            return Err(FakeBluetoothError::AlreadyExists(format!("Device {} already exists.", device.get_address()?)));
+        }
+        device.set_adapter(self)?;
+        device.update_paths(id);
+        ::lock(&self.devices).push(device.clone());
+        ::lock(&self.objects).extend(device.get_objects());
+        if device.is_discovered() {
+            self.emit(FakeBluetoothEvent::DeviceAdded(device.get_id()));
+        }
+        Ok(())
     }
 
-    pub fn get_can_stop_siscovery(&self) -> Result<bool, Box<Error>> {
-        Ok(self.can_stop_discovery)
+    fn device_path(&self, device: &FakeBluetoothDevice) -> Result<String, FakeBluetoothError> {
+        Ok(format!("{}/dev_{}", self.get_id(), device.get_address()?.replace(":", "_").to_uppercase()))
+    }
+
+    // Like Adapter1.RemoveDevice: the device is disconnected and unpaired
+    // first, then it and every GATT object beneath it stop existing.
+    pub fn remove_device(&self, id: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let device = match ::lock(&self.devices).iter().find(|device| device.get_id() == id) {
+            Some(device) => device.clone(),
+            None => return Err(FakeBluetoothError::NotFound(format!("No device found with id: {}.", id))),
+        };
+        device.forget();
+        ::lock(&self.devices).retain(|other| !Arc::ptr_eq(other, &device));
+        {
+            let mut objects = ::lock(&self.objects);
+            for (id, _) in device.get_objects() {
+                objects.remove(&id);
+            }
+        }
+        if device.is_discovered() {
+            self.emit(FakeBluetoothEvent::DeviceRemoved(device.get_id()));
+        }
+        device.invalidate();
+        Ok(())
     }
 
-    pub fn set_can_stop_discovery(&mut self, value: bool) {
-        self.can_stop_discovery = value;
+    pub fn get_device(&self, id: String) -> Result<Arc<FakeBluetoothDevice>, FakeBluetoothError> {
+        match self.get_object(&id) {
+            Some(FakeBluetoothObject::Device(device)) => Ok(device),
+            _ => Err(FakeBluetoothError::NotFound(format!("No device found with id: {}.", id))),
+        }
     }
 
-    pub fn get_device_list(&self) -> Result<Vec<Arc<FakeBluetoothDevice>>, Box<Error>> {
-        Ok(self.devices.clone())
+    pub fn get_service(&self, id: String) -> Result<Arc<FakeBluetoothGATTService>, FakeBluetoothError> {
+        match self.get_object(&id) {
+            Some(FakeBluetoothObject::Service(service)) => Ok(service),
+            _ => Err(FakeBluetoothError::NotFound(format!("No service found with id: {}.", id))),
+        }
     }
 
-    pub fn set_devices(&mut self, devices: Vec<Arc<FakeBluetoothDevice>>){
-        self.devices = devices;
+    pub fn get_characteristic(&self, id: String) -> Result<Arc<FakeBluetoothGATTCharacteristic>, FakeBluetoothError> {
+        match self.get_object(&id) {
+            Some(FakeBluetoothObject::Characteristic(characteristic)) => Ok(characteristic),
+            _ => Err(FakeBluetoothError::NotFound(format!("No characteristic found with id: {}.", id))),
+        }
     }
 
-    pub fn get_first_device(&self) -> Result<Arc<FakeBluetoothDevice>, Box<Error>> {
-        if self.devices.is_empty() {
-            return Err(Box::from("No device found."))
+    pub fn get_descriptor(&self, id: String) -> Result<Arc<FakeBluetoothGATTDescriptor>, FakeBluetoothError> {
+        match self.get_object(&id) {
+            Some(FakeBluetoothObject::Descriptor(descriptor)) => Ok(descriptor),
+            _ => Err(FakeBluetoothError::NotFound(format!("No descriptor found with id: {}.", id))),
         }
-        Ok(self.devices[0].clone())
     }
 
-    pub fn get_addatas(&self) -> Result<Vec<String>, Box<Error>> {
-        Ok(self.addatas.clone())
+    fn get_object(&self, id: &str) -> Option<FakeBluetoothObject> {
+        if self.check_valid().is_err() {
+            return None;
+        }
+        let object = ::lock(&self.objects).get(id).cloned();
+        object.filter(|object| object.is_visible())
+    }
+
+    // Swaps the registered children of `parent_id`, provided the parent
+    // itself is reachable from this adapter.
+    pub(crate) fn replace_objects(&self,
+                                  parent_id: &str,
+                                  old_objects: Vec<(String, FakeBluetoothObject)>,
+                                  new_objects: Vec<(String, FakeBluetoothObject)>) {
+        let mut objects = ::lock(&self.objects);
+        if !objects.contains_key(parent_id) {
+            return;
+        }
+        for (id, _) in old_objects {
+            objects.remove(&id);
+        }
+        objects.extend(new_objects);
     }
 
-    pub fn set_addatas(&mut self, addatas: Vec<String>) {
-        self.addatas = addatas;
+    pub(crate) fn rename_object(&self, old_id: &str, new_id: String) {
+        let mut objects = ::lock(&self.objects);
+        if let Some(object) = objects.remove(old_id) {
+            objects.insert(new_id, object);
+        }
     }
 
-    pub fn get_first_addata(&self) -> Result<String, Box<Error>> {
-        if self.addatas.is_empty() {
-            return Err(Box::from("No addata found."))
+    pub fn get_first_device(&self) -> Result<Arc<FakeBluetoothDevice>, FakeBluetoothError> {
+        self.check_valid()?;
+        match ::lock(&self.devices).iter().find(|device| device.is_discovered()) {
+            Some(device) => Ok(device.clone()),
+            None => Err(FakeBluetoothError::NotFound(String::from("No device found."))),
         }
-        Ok(self.addatas[0].clone())
     }
 
-    pub fn get_address(&self) -> Result<String, Box<Error>> {
-        Ok(self.address.clone())
+    make_getter!(get_addatas, addatas, Vec<String>);
+
+    make_setter!(set_addatas, addatas, Vec<String>);
+
+    pub fn get_first_addata(&self) -> Result<String, FakeBluetoothError> {
+        self.check_valid()?;
+        match ::lock(&self.addatas).first() {
+            Some(addata) => Ok(addata.clone()),
+            None => Err(FakeBluetoothError::NotFound(String::from("No addata found."))),
+        }
     }
 
-    pub fn set_address(&mut self, address: String) {
-        self.address = address;
+    make_getter!(get_address, address, String);
+
+    make_setter!(set_address, address, String, "Address", ::parse_address);
+
+    make_getter!(get_name, name, String);
+
+    make_setter!(set_name, name, String, "Name");
+
+    pub fn create_discovery_session(self: &Arc<Self>) -> Result<FakeBluetoothDiscoverySession, FakeBluetoothError> {
+        self.check_valid()?;
+        FakeBluetoothDiscoverySession::create_session(self.clone())
     }
 
-    pub fn get_name(&self) -> Result<String, Box<Error>> {
-        Ok(self.name.clone())
+    pub fn get_agent_manager(&self) -> Arc<FakeAgentManager> {
+        self.agent_manager.clone()
     }
 
-    pub fn set_name(&mut self, name: String) {
-        self.name = name;
+    make_getter!(get_alias, alias, String);
+
+    make_setter!(set_alias, alias, String, "Alias");
+
+    make_getter!(get_class, class, u32);
+
+    make_setter!(set_class, class, u32, "Class");
+
+    make_getter!(is_discoverable, is_discoverable, bool);
+
+    // Like the pairable mode, it can not change while powered off.
+    pub fn set_discoverable(&self, discoverable: bool) -> Result<(), FakeBluetoothError> {
+        self.check_powered()?;
+        self.update_mode(&self.is_discoverable, "Discoverable", discoverable);
+        Ok(())
     }
 
-    pub fn create_discovery_session(&self) -> Result<FakeBluetoothDiscoverySession, Box<Error>> {
-        FakeBluetoothDiscoverySession::create_session(Arc::new(self.clone()))
+    make_getter!(is_pairable, is_pairable, bool);
+
+    pub fn set_pairable(&self, pairable: bool) -> Result<(), FakeBluetoothError> {
+        self.check_powered()?;
+        self.update_mode(&self.is_pairable, "Pairable", pairable);
+        Ok(())
     }
 
-    pub fn get_alias(&self) -> Result<String, Box<Error>> {
-        Ok(self.alias.clone())
+    make_getter!(get_pairable_timeout, pairable_timeout, u32);
+
+    make_setter!(set_pairable_timeout, pairable_timeout, u32, "PairableTimeout");
+
+    make_getter!(get_discoverable_timeout, discoverable_timeout, u32);
+
+    make_setter!(set_discoverable_timeout, discoverable_timeout, u32, "DiscoverableTimeout");
+
+    // Only the discovery sessions change this, so it always matches them.
+    make_getter!(is_discovering, is_discovering, bool);
+
+    // Discovery is reference counted per session, the way BlueZ counts it per
+    // D-Bus client: it only stops when the last active session stops.
+    pub(crate) fn start_session_discovery(&self, session_id: usize, filter: Option<FakeDiscoveryFilter>) -> Result<(), FakeBluetoothError> {
+        self.check_powered()?;
+        let mut sessions = ::lock(&self.discovery_sessions);
+        if sessions.is_empty() {
+            if !*::lock(&self.can_start_discovery) {
+                return Err(FakeBluetoothError::Failed(String::from("Could not start discovery.")));
+            }
+            self.update_discovering(true);
+            *::lock(&self.discovery_started) = Some(Instant::now());
+            *::lock(&self.discovery_polls) = 0;
+        }
+        sessions.insert(session_id, filter);
+        drop(sessions);
+        self.update_discovered_devices();
+        Ok(())
     }
 
-    pub fn set_alias(&mut self, value: String) -> Result<(), Box<Error>> {
-        self.alias = value;
+    pub(crate) fn stop_session_discovery(&self, session_id: usize) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if ::lock(&self.discovery_sessions).len() == 1 && !*::lock(&self.can_stop_discovery) {
+            return Err(FakeBluetoothError::Failed(String::from("Could not stop discovery.")));
+        }
+        self.release_session_discovery(session_id);
         Ok(())
     }
 
-    pub fn get_class(&self) -> Result<u32, Box<Error>> {
-        Ok(self.class)
+    pub(crate) fn release_session_discovery(&self, session_id: usize) {
+        let mut sessions = ::lock(&self.discovery_sessions);
+        if sessions.remove(&session_id).is_some() && sessions.is_empty() {
+            drop(sessions);
+            self.end_discovery();
+        }
     }
 
-    pub fn set_class(&mut self, value: u32) {
-        self.class = value;
+    pub(crate) fn is_session_active(&self, session_id: usize) -> bool {
+        ::lock(&self.discovery_sessions).contains_key(&session_id)
     }
 
-    pub fn is_discoverable(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_discoverable)
+    fn end_discovery(&self) {
+        self.update_discovering(false);
+        *::lock(&self.discovery_started) = None;
+        for device in ::lock(&self.devices).iter() {
+            device.discovery_stopped();
+        }
     }
 
-    pub fn set_discoverable(&mut self, value: bool) -> Result<(), Box<Error>> {
-        self.is_discoverable = value;
-        Ok(())
+    pub(crate) fn update_session_filter(&self, session_id: usize, filter: Option<FakeDiscoveryFilter>) {
+        if let Some(session_filter) = ::lock(&self.discovery_sessions).get_mut(&session_id) {
+            *session_filter = filter;
+        }
+        self.update_discovered_devices();
     }
 
-    pub fn is_pairable(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_pairable)
+    // The filter the active sessions' filters merge into; None when
+    // discovering unfiltered or not at all.
+    pub fn get_discovery_filter(&self) -> Result<Option<FakeDiscoveryFilter>, FakeBluetoothError> {
+        self.check_valid()?;
+        let sessions = ::lock(&self.discovery_sessions);
+        if sessions.is_empty() {
+            return Ok(None);
+        }
+        Ok(FakeDiscoveryFilter::merge(sessions.values()))
     }
 
-    pub fn set_pairable(&mut self, value: bool) -> Result<(), Box<Error>> {
-        self.is_pairable = value;
-        Ok(())
+    fn update_discovered_devices(&self) {
+        let discovery = ::lock(&self.discovery_started).map(|started| (*::lock(&self.discovery_polls), started.elapsed()));
+        let filter = match self.get_discovery_filter() {
+            Ok(filter) => filter,
+            Err(_) => return,
+        };
+        for device in ::lock(&self.devices).iter() {
+            device.update_discovered(discovery, filter.as_ref());
+        }
     }
 
-    pub fn get_pairable_timeout(&self) -> Result<u32, Box<Error>> {
-        Ok(self.pairable_timeout)
+    make_getter!(get_uuids, uuids, Vec<String>);
+
+    make_setter!(set_uuids, uuids, Vec<String>, "UUIDs", ::parse_uuids);
+
+    pub fn get_modalias(&self) -> Result<Modalias, FakeBluetoothError> {
+        self.check_valid()?;
+        let modalias = ::lock(&self.modalias).clone();
+        if modalias.is_empty() {
+            return Err(FakeBluetoothError::NotFound(String::from("No modalias found.")));
+        }
+        modalias.parse()
     }
 
-    pub fn set_pairable_timeout(&mut self, value: u32) -> Result<(), Box<Error>> {
-        self.pairable_timeout = value;
-        Ok(())
+    make_setter!(set_modalias, modalias, String, "Modalias", ::parse_modalias);
+
+    pub fn get_vendor_id_source(&self) -> Result<String, FakeBluetoothError> {
+        Ok(self.get_modalias()?.get_source())
+    }
+
+    pub fn get_vendor_id(&self) -> Result<u32, FakeBluetoothError> {
+        match self.get_modalias()?.get_vendor() {
+            Some(vendor_id) => Ok(vendor_id as u32),
+            None => Err(FakeBluetoothError::NotFound(String::from("No vendor id found."))),
+        }
+    }
+
+    pub fn get_product_id(&self) -> Result<u32, FakeBluetoothError> {
+        match self.get_modalias()?.get_product() {
+            Some(product_id) => Ok(product_id as u32),
+            None => Err(FakeBluetoothError::NotFound(String::from("No product id found."))),
+        }
     }
 
-    pub fn get_discoverable_timeout(&self) -> Result<u32, Box<Error>> {
-        Ok(self.discoverable_timeout)
+    pub fn get_device_id(&self) -> Result<u32, FakeBluetoothError> {
+        match self.get_modalias()?.get_device() {
+            Some(device_id) => Ok(device_id as u32),
+            None => Err(FakeBluetoothError::NotFound(String::from("No device id found."))),
+        }
     }
 
-    pub fn set_discoverable_timeout(&mut self, value: u32) -> Result<(), Box<Error>> {
-        self.discoverable_timeout = value;
+    fn update_discovering(&self, discovering: bool) {
+        if ::update(&self.is_discovering, discovering).is_some() {
+            self.property_changed("Discovering", (!discovering).into(), discovering.into());
+        }
+    }
+
+    // Every subscriber gets its own copy of each event from then on; a
+    // subscriber is forgotten once its receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<FakeBluetoothEvent> {
+        let (sender, receiver) = mpsc::channel();
+        ::lock(&self.subscribers).push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit(&self, event: FakeBluetoothEvent) {
+        ::lock(&self.subscribers).retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn property_changed(&self, property: &str, old_value: FakePropertyValue, new_value: FakePropertyValue) {
+        self.emit(FakeBluetoothEvent::PropertyChanged {
+            object_path: self.get_id(),
+            property: String::from(property),
+            old_value,
+            new_value,
+        });
+    }
+
+    fn check_valid(&self) -> Result<(), FakeBluetoothError> {
+        if !*::lock(&self.is_present) {
+            return Err(FakeBluetoothError::NotFound(format!("The adapter {} is not present.", self.get_id())));
+        }
         Ok(())
     }
+}
 
-    pub fn is_discovering(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_discovering)
+#[derive(Clone, Debug)]
+pub struct FakeBluetoothAdapterBuilder {
+    object_path: String,
+    is_present: bool,
+    is_powered: bool,
+    can_start_discovery: bool,
+    can_stop_discovery: bool,
+    addatas: Vec<String>,
+    address: String,
+    name: String,
+    alias: String,
+    class: u32,
+    is_discoverable: bool,
+    is_pairable: bool,
+    pairable_timeout: u32,
+    discoverable_timeout: u32,
+    uuids: Vec<String>,
+    modalias: String,
+}
+
+impl Default for FakeBluetoothAdapterBuilder {
+    fn default() -> FakeBluetoothAdapterBuilder {
+        FakeBluetoothAdapterBuilder {
+            object_path: String::from("/org/bluez/hci0"),
+            is_present: true,
+            is_powered: true,
+            can_start_discovery: true,
+            can_stop_discovery: true,
+            addatas: vec![],
+            address: String::from("00:00:00:00:00:00"),
+            name: String::new(),
+            alias: String::new(),
+            class: 0,
+            is_discoverable: false,
+            is_pairable: false,
+            pairable_timeout: 0,
+            discoverable_timeout: 180,
+            uuids: vec![],
+            modalias: String::new(),
+        }
+    }
+}
+
+impl FakeBluetoothAdapterBuilder {
+    pub fn new() -> FakeBluetoothAdapterBuilder {
+        FakeBluetoothAdapterBuilder::default()
+    }
+
+    pub fn id(mut self, object_path: String) -> FakeBluetoothAdapterBuilder {
+        self.object_path = object_path;
+        self
+    }
+
+    pub fn present(mut self, value: bool) -> FakeBluetoothAdapterBuilder {
+        self.is_present = value;
+        self
     }
 
-    pub fn set_discovering(&mut self, value: bool) {
-        self.is_discovering = value;
+    pub fn powered(mut self, value: bool) -> FakeBluetoothAdapterBuilder {
+        self.is_powered = value;
+        self
     }
 
-    pub fn get_uuids(&self) -> Result<Vec<String>, Box<Error>> {
-        Ok(self.uuids.clone())
+    pub fn can_start_discovery(mut self, value: bool) -> FakeBluetoothAdapterBuilder {
+        self.can_start_discovery = value;
+        self
     }
 
-    pub fn set_uuids(&mut self, value: Vec<String>) {
-        self.uuids = value;
+    pub fn can_stop_discovery(mut self, value: bool) -> FakeBluetoothAdapterBuilder {
+        self.can_stop_discovery = value;
+        self
     }
 
-    pub fn get_modalias(&self) ->  Result<(String, u32, u32, u32), Box<Error>> {
-        let ids: Vec<&str> = self.modalias.split(":").collect();
+    pub fn addatas(mut self, addatas: Vec<String>) -> FakeBluetoothAdapterBuilder {
+        self.addatas = addatas;
+        self
+    }
 
-        let source = String::from(ids[0]);
-        let vendor = ids[1][1..5].from_hex().unwrap();
-        let product = ids[1][6..10].from_hex().unwrap();
-        let device = ids[1][11..15].from_hex().unwrap();
+    pub fn address(mut self, address: String) -> FakeBluetoothAdapterBuilder {
+        self.address = address;
+        self
+    }
 
-        Ok((source,
-        (vendor[0] as u32) * 16 * 16 + (vendor[1] as u32),
-        (product[0] as u32) * 16 * 16 + (product[1] as u32),
-        (device[0] as u32) * 16 * 16 + (device[1] as u32)))
+    pub fn name(mut self, name: String) -> FakeBluetoothAdapterBuilder {
+        self.name = name;
+        self
     }
 
-    pub fn set_modalias(&mut self, value: String) {
-        self.modalias = value;
+    pub fn alias(mut self, alias: String) -> FakeBluetoothAdapterBuilder {
+        self.alias = alias;
+        self
     }
 
-    pub fn get_vendor_id_source(&self) -> Result<String, Box<Error>> {
-        let (vendor_id_source,_,_,_) = try!(self.get_modalias());
-        Ok(vendor_id_source)
+    pub fn class(mut self, class: u32) -> FakeBluetoothAdapterBuilder {
+        self.class = class;
+        self
     }
 
-    pub fn get_vendor_id(&self) -> Result<u32, Box<Error>> {
-        let (_,vendor_id,_,_) = try!(self.get_modalias());
-        Ok(vendor_id)
+    pub fn discoverable(mut self, value: bool) -> FakeBluetoothAdapterBuilder {
+        self.is_discoverable = value;
+        self
     }
 
-    pub fn get_product_id(&self) -> Result<u32, Box<Error>> {
-        let (_,_,product_id,_) = try!(self.get_modalias());
-        Ok(product_id)
+    pub fn pairable(mut self, value: bool) -> FakeBluetoothAdapterBuilder {
+        self.is_pairable = value;
+        self
     }
 
-    pub fn get_device_id(&self) -> Result<u32, Box<Error>> {
-        let (_,_,_,device_id) = try!(self.get_modalias());
-        Ok(device_id)
+    pub fn pairable_timeout(mut self, timeout: u32) -> FakeBluetoothAdapterBuilder {
+        self.pairable_timeout = timeout;
+        self
+    }
+
+    pub fn discoverable_timeout(mut self, timeout: u32) -> FakeBluetoothAdapterBuilder {
+        self.discoverable_timeout = timeout;
+        self
+    }
+
+    pub fn uuids(mut self, uuids: Vec<String>) -> FakeBluetoothAdapterBuilder {
+        self.uuids = uuids;
+        self
+    }
+
+    pub fn modalias(mut self, modalias: String) -> FakeBluetoothAdapterBuilder {
+        self.modalias = modalias;
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<FakeBluetoothAdapter>, FakeBluetoothBuildError> {
+        let address = ::parse_address(&self.address)?;
+        let uuids = ::parse_uuids(&self.uuids)?;
+        let modalias = ::parse_modalias(&self.modalias)?;
+        Ok(Arc::new(FakeBluetoothAdapter {
+            object_path: Mutex::new(self.object_path),
+            is_present: Mutex::new(self.is_present),
+            is_powered: Mutex::new(self.is_powered),
+            can_start_discovery: Mutex::new(self.can_start_discovery),
+            can_stop_discovery: Mutex::new(self.can_stop_discovery),
+            addatas: Mutex::new(self.addatas),
+            address: Mutex::new(address),
+            name: Mutex::new(self.name),
+            alias: Mutex::new(self.alias),
+            class: Mutex::new(self.class),
+            is_discoverable: Mutex::new(self.is_discoverable),
+            is_pairable: Mutex::new(self.is_pairable),
+            pairable_timeout: Mutex::new(self.pairable_timeout),
+            discoverable_timeout: Mutex::new(self.discoverable_timeout),
+            uuids: Mutex::new(uuids),
+            modalias: Mutex::new(modalias),
+            ..FakeBluetoothAdapter::new_empty()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FakeBluetoothAdapter, FakeBluetoothAdapterBuilder};
+    use fake_device::{FakeBluetoothDeviceBuilder, FakeDiscoverySchedule};
+    use fake_error::FakeBluetoothError;
+
+    #[test]
+    fn set_devices_changes_nothing_when_a_device_can_not_be_added() {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let kept = FakeBluetoothDeviceBuilder::new().address(String::from("00:00:00:00:00:01")).build().unwrap();
+        adapter.set_devices(vec![kept.clone()]).unwrap();
+        let first = FakeBluetoothDeviceBuilder::new().address(String::from("00:00:00:00:00:02")).build().unwrap();
+        let second = FakeBluetoothDeviceBuilder::new().address(String::from("00:00:00:00:00:02")).build().unwrap();
+        assert!(adapter.set_devices(vec![first.clone(), second.clone()]).is_err());
+        assert!(adapter.set_devices(vec![first.clone(), first.clone()]).is_err());
+        assert!(first.get_adapter().is_err());
+        assert!(kept.get_adapter().is_ok());
+        assert_eq!(adapter.get_device_list().unwrap().len(), 1);
+        adapter.set_devices(vec![first.clone()]).unwrap();
+        assert!(kept.get_adapter().is_err());
+        assert!(first.get_adapter().is_ok());
+    }
+
+    #[test]
+    fn discovery_fails_when_the_controller_refuses() {
+        let adapter = FakeBluetoothAdapterBuilder::new().can_start_discovery(false).can_stop_discovery(false).build().unwrap();
+        let session = adapter.create_discovery_session().unwrap();
+        assert!(session.start_discovery().is_err());
+        assert_eq!(adapter.is_discovering(), Ok(false));
+        adapter.set_can_start_discovery(true).unwrap();
+        session.start_discovery().unwrap();
+        assert!(session.stop_discovery().is_err());
+        assert!(session.is_active());
+        assert_eq!(adapter.is_discovering(), Ok(true));
+    }
+
+    #[test]
+    fn discovery_ends_with_the_last_session() {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let first = adapter.create_discovery_session().unwrap();
+        let second = adapter.create_discovery_session().unwrap();
+        first.start_discovery().unwrap();
+        second.start_discovery().unwrap();
+        assert!(second.start_discovery().is_err());
+        first.stop_discovery().unwrap();
+        assert!(first.stop_discovery().is_err());
+        assert_eq!(adapter.is_discovering(), Ok(true));
+        drop(second);
+        assert_eq!(adapter.is_discovering(), Ok(false));
+    }
+
+    fn device_ids(adapter: &FakeBluetoothAdapter) -> Vec<String> {
+        adapter.get_device_list().unwrap().iter().map(|device| device.get_id()).collect()
+    }
+
+    #[test]
+    fn device_appears_after_polls() {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new().discovery_schedule(FakeDiscoverySchedule::AfterPolls(2)).build().unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        assert!(device_ids(&adapter).is_empty());
+        let session = adapter.create_discovery_session().unwrap();
+        session.start_discovery().unwrap();
+        assert!(device_ids(&adapter).is_empty());
+        assert_eq!(device_ids(&adapter), vec![device.get_id()]);
+        // Found devices stay listed after discovery stops.
+        session.stop_discovery().unwrap();
+        assert_eq!(device_ids(&adapter), vec![device.get_id()]);
+    }
+
+    #[test]
+    fn device_is_listed_only_while_discovering() {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new().discovery_schedule(FakeDiscoverySchedule::WhileDiscovering).build().unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        assert!(device_ids(&adapter).is_empty());
+        let session = adapter.create_discovery_session().unwrap();
+        session.start_discovery().unwrap();
+        assert_eq!(device_ids(&adapter), vec![device.get_id()]);
+        session.stop_discovery().unwrap();
+        assert!(device_ids(&adapter).is_empty());
+    }
+
+    #[test]
+    fn device_disappears_on_stop() {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new().disappears_on_stop(true).build().unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        assert_eq!(device_ids(&adapter), vec![device.get_id()]);
+        let session = adapter.create_discovery_session().unwrap();
+        session.start_discovery().unwrap();
+        session.stop_discovery().unwrap();
+        assert!(device_ids(&adapter).is_empty());
+        session.start_discovery().unwrap();
+        assert_eq!(device_ids(&adapter), vec![device.get_id()]);
+    }
+
+    #[test]
+    fn absent_adapter_hides_its_devices() {
+        let adapter = FakeBluetoothAdapterBuilder::new().addatas(vec![String::from("addata")]).build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new().build().unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        adapter.set_present(false).unwrap();
+        assert!(adapter.get_first_device().is_err());
+        assert!(adapter.get_first_addata().is_err());
+        assert!(adapter.set_devices(vec![]).is_err());
+        assert!(adapter.create_discovery_session().is_err());
+        adapter.set_present(true).unwrap();
+        assert!(adapter.get_first_device().is_ok());
+    }
+
+    #[test]
+    fn powering_off_disconnects_devices_and_ends_discovery() {
+        let adapter = FakeBluetoothAdapterBuilder::new().discoverable(true).build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new().build().unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        device.connect().unwrap();
+        let session = adapter.create_discovery_session().unwrap();
+        session.start_discovery().unwrap();
+        adapter.set_powered(false).unwrap();
+        assert_eq!(device.is_connected(), Ok(false));
+        assert!(!session.is_active());
+        assert_eq!(adapter.is_discovering(), Ok(false));
+        assert_eq!(adapter.is_discoverable(), Ok(false));
+        adapter.set_powered(true).unwrap();
+        assert_eq!(adapter.is_discoverable(), Ok(true));
+    }
+
+    #[test]
+    fn modes_can_not_change_while_powered_off() {
+        let adapter = FakeBluetoothAdapterBuilder::new().discoverable(true).pairable(true).build().unwrap();
+        adapter.set_powered(false).unwrap();
+        assert_eq!(adapter.set_discoverable(false), Err(FakeBluetoothError::NotPowered));
+        assert_eq!(adapter.set_pairable(false), Err(FakeBluetoothError::NotPowered));
+        adapter.set_powered(true).unwrap();
+        assert_eq!(adapter.is_discoverable(), Ok(true));
+        assert_eq!(adapter.is_pairable(), Ok(true));
+        adapter.set_discoverable(false).unwrap();
+        assert_eq!(adapter.is_discoverable(), Ok(false));
     }
 }
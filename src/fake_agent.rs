@@ -0,0 +1,209 @@
+use fake_error::FakeBluetoothError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// How an agent can turn a request down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FakeAgentError {
+    Rejected,
+    Canceled,
+    TimedOut,
+}
+
+// The callbacks of org.bluez.Agent1, each getting the object path of the
+// device being paired. Anything not implemented is rejected.
+pub trait FakeAgent: fmt::Debug + Send + Sync {
+    fn request_pin_code(&self, _device: &str) -> Result<String, FakeAgentError> {
+        Err(FakeAgentError::Rejected)
+    }
+
+    fn request_passkey(&self, _device: &str) -> Result<u32, FakeAgentError> {
+        Err(FakeAgentError::Rejected)
+    }
+
+    fn request_confirmation(&self, _device: &str, _passkey: u32) -> Result<(), FakeAgentError> {
+        Err(FakeAgentError::Rejected)
+    }
+
+    fn request_authorization(&self, _device: &str) -> Result<(), FakeAgentError> {
+        Err(FakeAgentError::Rejected)
+    }
+
+    // Asked before an untrusted device may use the service with the UUID.
+    fn authorize_service(&self, _device: &str, _uuid: &str) -> Result<(), FakeAgentError> {
+        Err(FakeAgentError::Rejected)
+    }
+
+    // Shows the passkey the device is asked to enter.
+    fn display_passkey(&self, _device: &str, _passkey: u32) {}
+
+    // Called when the request the agent answered has been canceled.
+    fn cancel(&self) {}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FakeIoCapability {
+    DisplayOnly,
+    DisplayYesNo,
+    KeyboardOnly,
+    #[default]
+    NoInputNoOutput,
+    KeyboardDisplay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FakePairingMethod {
+    JustWorks,
+    PasskeyEntry,
+    NumericComparison,
+}
+
+impl FakeIoCapability {
+    // The LE Secure Connections association model for pairing a device with
+    // `device` capabilities from this side.
+    pub fn pairing_method(self, device: FakeIoCapability) -> FakePairingMethod {
+        use self::FakeIoCapability::*;
+        match (self, device) {
+            (NoInputNoOutput, _) | (_, NoInputNoOutput) => FakePairingMethod::JustWorks,
+            (DisplayOnly, DisplayOnly) | (DisplayOnly, DisplayYesNo) | (DisplayYesNo, DisplayOnly) => {
+                FakePairingMethod::JustWorks
+            },
+            (DisplayYesNo, DisplayYesNo) |
+            (DisplayYesNo, KeyboardDisplay) |
+            (KeyboardDisplay, DisplayYesNo) |
+            (KeyboardDisplay, KeyboardDisplay) => FakePairingMethod::NumericComparison,
+            _ => FakePairingMethod::PasskeyEntry,
+        }
+    }
+
+    // With passkey entry, whether this side types the passkey in rather than
+    // displaying it for the device to type.
+    pub(crate) fn enters_passkey(self, device: FakeIoCapability) -> bool {
+        match self {
+            FakeIoCapability::KeyboardOnly => true,
+            FakeIoCapability::KeyboardDisplay => device != FakeIoCapability::KeyboardOnly,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FakeRegisteredAgent {
+    path: String,
+    agent: Arc<dyn FakeAgent>,
+    capability: FakeIoCapability,
+}
+
+// Modelled on org.bluez.AgentManager1, with agents registered under an
+// object path of their own.
+#[derive(Debug, Default)]
+pub struct FakeAgentManager {
+    agents: Mutex<Vec<FakeRegisteredAgent>>,
+    default_agent: Mutex<Option<String>>,
+}
+
+impl FakeAgentManager {
+    pub fn new() -> FakeAgentManager {
+        FakeAgentManager::default()
+    }
+
+    pub fn register_agent(&self,
+                          path: String,
+                          agent: Arc<dyn FakeAgent>,
+                          capability: FakeIoCapability)
+                          -> Result<(), FakeBluetoothError> {
+        let mut agents = ::lock(&self.agents);
+        if agents.iter().any(|registered| registered.path == path) {
+            return Err(FakeBluetoothError::AlreadyExists(format!("Agent {} already registered.", path)));
+        }
+        agents.push(FakeRegisteredAgent {
+            path,
+            agent,
+            capability,
+        });
+        Ok(())
+    }
+
+    pub fn unregister_agent(&self, path: String) -> Result<(), FakeBluetoothError> {
+        let mut agents = ::lock(&self.agents);
+        match agents.iter().position(|registered| registered.path == path) {
+            Some(index) => agents.remove(index),
+            None => return Err(FakeBluetoothError::NotFound(format!("No agent registered with path: {}.", path))),
+        };
+        let mut default_agent = ::lock(&self.default_agent);
+        if default_agent.as_ref() == Some(&path) {
+            *default_agent = None;
+        }
+        Ok(())
+    }
+
+    pub fn request_default_agent(&self, path: String) -> Result<(), FakeBluetoothError> {
+        if !::lock(&self.agents).iter().any(|registered| registered.path == path) {
+            return Err(FakeBluetoothError::NotFound(format!("No agent registered with path: {}.", path)));
+        }
+        *::lock(&self.default_agent) = Some(path);
+        Ok(())
+    }
+
+    pub fn get_default_agent_path(&self) -> Option<String> {
+        self.get_default_agent().map(|registered| registered.path)
+    }
+
+    // The agent asked to be the default, or else the earliest registered one.
+    fn get_default_agent(&self) -> Option<FakeRegisteredAgent> {
+        let agents = ::lock(&self.agents);
+        let default_agent = ::lock(&self.default_agent);
+        agents.iter()
+              .find(|registered| default_agent.as_ref() == Some(&registered.path))
+              .or_else(|| agents.first())
+              .cloned()
+    }
+
+    pub(crate) fn get_agent(&self) -> Option<(Arc<dyn FakeAgent>, FakeIoCapability)> {
+        self.get_default_agent().map(|registered| (registered.agent, registered.capability))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FakeIoCapability::*;
+    use super::FakePairingMethod::*;
+
+    // The LE Secure Connections mapping of IO capabilities to association
+    // models (Core spec Vol 3, Part H, 2.3.5.1), this side first. For
+    // passkey entry, whether this side is the one typing the passkey in.
+    #[test]
+    fn pairing_method_follows_the_association_model_table() {
+        let table = [(DisplayOnly, DisplayOnly, JustWorks, false),
+                     (DisplayOnly, DisplayYesNo, JustWorks, false),
+                     (DisplayOnly, KeyboardOnly, PasskeyEntry, false),
+                     (DisplayOnly, NoInputNoOutput, JustWorks, false),
+                     (DisplayOnly, KeyboardDisplay, PasskeyEntry, false),
+                     (DisplayYesNo, DisplayOnly, JustWorks, false),
+                     (DisplayYesNo, DisplayYesNo, NumericComparison, false),
+                     (DisplayYesNo, KeyboardOnly, PasskeyEntry, false),
+                     (DisplayYesNo, NoInputNoOutput, JustWorks, false),
+                     (DisplayYesNo, KeyboardDisplay, NumericComparison, false),
+                     (KeyboardOnly, DisplayOnly, PasskeyEntry, true),
+                     (KeyboardOnly, DisplayYesNo, PasskeyEntry, true),
+                     (KeyboardOnly, KeyboardOnly, PasskeyEntry, true),
+                     (KeyboardOnly, NoInputNoOutput, JustWorks, false),
+                     (KeyboardOnly, KeyboardDisplay, PasskeyEntry, true),
+                     (NoInputNoOutput, DisplayOnly, JustWorks, false),
+                     (NoInputNoOutput, DisplayYesNo, JustWorks, false),
+                     (NoInputNoOutput, KeyboardOnly, JustWorks, false),
+                     (NoInputNoOutput, NoInputNoOutput, JustWorks, false),
+                     (NoInputNoOutput, KeyboardDisplay, JustWorks, false),
+                     (KeyboardDisplay, DisplayOnly, PasskeyEntry, true),
+                     (KeyboardDisplay, DisplayYesNo, NumericComparison, false),
+                     (KeyboardDisplay, KeyboardOnly, PasskeyEntry, false),
+                     (KeyboardDisplay, NoInputNoOutput, JustWorks, false),
+                     (KeyboardDisplay, KeyboardDisplay, NumericComparison, false)];
+        for &(local, device, method, enters_passkey) in table.iter() {
+            assert_eq!(local.pairing_method(device), method, "{:?} pairing {:?}", local, device);
+            if method == PasskeyEntry {
+                assert_eq!(local.enters_passkey(device), enters_passkey, "{:?} pairing {:?}", local, device);
+            }
+        }
+    }
+}
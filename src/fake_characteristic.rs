@@ -1,114 +1,704 @@
+use fake_adapter::{FakeBluetoothAdapter, FakeBluetoothObject};
 use fake_descriptor::FakeBluetoothGATTDescriptor;
+use fake_device::FakeBluetoothDevice;
+use fake_error::{FakeBluetoothBuildError, FakeBluetoothError, FakeBluetoothOperation};
+use fake_event::{FakeBluetoothEvent, FakePropertyValue};
+use fake_generator::{FakeClock, FakeGeneratorTask, FakeValueGenerator};
+use fake_handler::{FakeAttributeHandlers, FakeAttributeValue};
+use fake_options::{FakeReadOptions, FakeWriteOptions, FakeWriteType};
 use fake_service::FakeBluetoothGATTService;
-use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
 
-#[derive(Clone, Debug)]
+static NEXT_NOTIFY_SESSION_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The offset and value of a write queued by a reliable write.
+pub(crate) type FakePreparedWrite = (u16, Vec<u8>);
+
+#[derive(Debug)]
 pub struct FakeBluetoothGATTCharacteristic {
-    object_path: String,
-    uuid: String,
-    service: Arc<FakeBluetoothGATTService>,
-    value: Vec<u8>,
-    is_notifying: bool,
-    flags: Vec<String>,
-    descriptors: Vec<Arc<FakeBluetoothGATTDescriptor>>,
+    object_path: Mutex<String>,
+    uuid: Mutex<String>,
+    service: Mutex<Weak<FakeBluetoothGATTService>>,
+    value: Mutex<Vec<u8>>,
+    is_notifying: Mutex<bool>,
+    flags: Mutex<Vec<String>>,
+    max_length: Mutex<usize>,
+    descriptors: Mutex<Vec<Arc<FakeBluetoothGATTDescriptor>>>,
+    notify_subscribers: Mutex<Vec<(usize, Sender<Vec<u8>>)>>,
+    generator: Mutex<Option<Arc<FakeGeneratorTask>>>,
+    handlers: FakeAttributeHandlers,
+    is_removed: Mutex<bool>,
 }
 
 impl FakeBluetoothGATTCharacteristic {
-    pub fn new(object_path: String,
-               uuid: String,
-               service: Arc<FakeBluetoothGATTService>,
-               value: Vec<u8>,
-               is_notifying: bool,
-               flags: Vec<String>,
-               descriptors: Vec<Arc<FakeBluetoothGATTDescriptor>>)
-               -> FakeBluetoothGATTCharacteristic {
+    pub fn new_empty() -> FakeBluetoothGATTCharacteristic {
         FakeBluetoothGATTCharacteristic {
-            object_path: object_path,
-            uuid: uuid,
-            service: service,
-            value: value,
-            is_notifying: is_notifying,
-            flags: flags,
-            descriptors: descriptors,
+            object_path: Mutex::new(String::new()),
+            uuid: Mutex::new(String::new()),
+            service: Mutex::new(Weak::new()),
+            value: Mutex::new(vec![]),
+            is_notifying: Mutex::new(false),
+            flags: Mutex::new(vec![]),
+            max_length: Mutex::new(::MAX_ATTRIBUTE_LENGTH),
+            descriptors: Mutex::new(vec![]),
+            notify_subscribers: Mutex::new(vec![]),
+            generator: Mutex::new(None),
+            handlers: FakeAttributeHandlers::default(),
+            is_removed: Mutex::new(false),
         }
     }
 
-    pub fn new_empty() -> FakeBluetoothGATTCharacteristic {
-        FakeBluetoothGATTCharacteristic {
-            object_path: String::new(),
-            uuid: String::new(),
-            service: Arc::new(FakeBluetoothGATTService::new_empty()),
-            value: vec![],
-            is_notifying: false,
-            flags: vec![],
-            descriptors: vec![],
+    pub fn get_id(&self) -> String {
+        ::lock(&self.object_path).clone()
+    }
+
+    pub fn set_id(&self, path: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let old_path = ::replace(&self.object_path, path.clone());
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.rename_object(&old_path, path);
         }
+        Ok(())
     }
 
-    pub fn get_id(&self) -> String {
-        self.object_path.clone()
+    make_getter!(get_uuid, uuid, String);
+
+    make_setter!(set_uuid, uuid, String, "UUID", ::parse_uuid);
+
+    pub fn get_service(&self) -> Result<Arc<FakeBluetoothGATTService>, FakeBluetoothError> {
+        self.check_valid()?;
+        match ::lock(&self.service).upgrade() {
+            Some(service) => Ok(service),
+            None => Err(FakeBluetoothError::NotFound(String::from("No service found."))),
+        }
     }
 
-    pub fn set_id(&mut self, path: String) {
-        self.object_path = path;
+    pub(crate) fn check_unattached(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if ::lock(&self.service).upgrade().is_some() {
+            return Err(FakeBluetoothError::AlreadyExists(format!("Characteristic {} already belongs to a service.", self.get_id())));
+        }
+        Ok(())
     }
 
-    pub fn get_uuid(&self) -> Result<String, Box<Error>> {
-        Ok(self.uuid.clone())
+    pub fn set_service(&self, service: &Arc<FakeBluetoothGATTService>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        *::lock(&self.service) = Arc::downgrade(service);
+        Ok(())
     }
 
-    pub fn set_uuid(&mut self, uuid: String) {
-        self.uuid = uuid;
+    make_getter!(get_value, value, Vec<u8>);
+
+    pub fn set_value(&self, value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        *::lock(&self.value) = value.clone();
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.emit(FakeBluetoothEvent::ValueChanged {
+                object_path: self.get_id(),
+                value,
+            });
+        }
+        Ok(())
     }
 
-    pub fn get_service(&self) -> Result<Arc<FakeBluetoothGATTService>, Box<Error>> {
-        Ok(self.service.clone())
+    make_getter!(is_notifying, is_notifying, bool);
+
+    make_setter!(set_is_notifying, is_notifying, bool, "Notifying");
+
+    make_getter!(get_flags, flags, Vec<String>);
+
+    make_setter!(set_flags, flags, Vec<String>, "Flags", ::parse_characteristic_flags);
+
+    make_getter!(get_max_length, max_length, usize);
+
+    make_setter!(set_max_length, max_length, usize);
+
+    make_getter!(get_gatt_descriptors, descriptors, Vec<Arc<FakeBluetoothGATTDescriptor>>);
+
+    // Works like remove_descriptor() and add_descriptor() for whatever
+    // changed, once every new descriptor is known to be addable.
+    pub fn set_gatt_descriptors(self: &Arc<Self>, descriptors: Vec<Arc<FakeBluetoothGATTDescriptor>>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let (removed, added) = ::diff_children(&::lock(&self.descriptors), &descriptors)?;
+        added.iter().try_for_each(|descriptor| descriptor.check_unattached())?;
+        for descriptor in removed {
+            self.remove_descriptor(descriptor.get_id())?;
+        }
+        for descriptor in added {
+            self.add_descriptor(descriptor)?;
+        }
+        *::lock(&self.descriptors) = descriptors;
+        Ok(())
     }
 
-    pub fn set_service(&mut self, service: Arc<FakeBluetoothGATTService>) {
-        self.service = service;
+    pub fn read_value(&self) -> Result<Vec<u8>, FakeBluetoothError> {
+        self.read_value_with_options(FakeReadOptions::new())
     }
 
-    pub fn get_value(&self) -> Result<Vec<u8>, Box<Error>> {
-        Ok(self.value.clone())
+    pub fn read_value_with_options(&self, options: FakeReadOptions) -> Result<Vec<u8>, FakeBluetoothError> {
+        self.get_device()?.check_gatt_access()?;
+        if !self.has_flag(&["read"]) {
+            return Err(FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Read));
+        }
+        self.handle_read(options.get_offset())
     }
 
-    pub fn set_value(&mut self, value: Vec<u8>) {
-        self.value = value;
+    pub fn write_value(&self, value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        self.write_value_with_options(value, FakeWriteOptions::new())
     }
 
-    pub fn is_notifying(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_notifying)
+    // A command gets no response, so whatever the peripheral makes of it
+    // the write succeeds once the flags allow it.
+    pub fn write_value_with_options(&self, value: Vec<u8>, options: FakeWriteOptions) -> Result<(), FakeBluetoothError> {
+        self.get_device()?.check_gatt_access()?;
+        let write_type = match options.get_write_type() {
+            Some(write_type) => write_type,
+            None if self.has_flag(&["write"]) => FakeWriteType::Request,
+            None => FakeWriteType::Command,
+        };
+        let permitted = match write_type {
+            FakeWriteType::Command => self.has_flag(&["write-without-response", "authenticated-signed-writes"]),
+            FakeWriteType::Request => self.has_flag(&["write"]),
+            FakeWriteType::Reliable => self.has_flag(&["reliable-write"]),
+        };
+        if !permitted {
+            return Err(FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Write));
+        }
+        // Unlike requests, commands are not split into prepared writes, so
+        // they have to fit into a single packet.
+        let payload_size = self.get_device()?.get_mtu()? as usize - 3;
+        if write_type == FakeWriteType::Command && value.len() > payload_size {
+            return Err(FakeBluetoothError::Failed(format!("The value is longer than the {} bytes the MTU allows.", payload_size)));
+        }
+        let result = self.handle_write(options.get_offset(), value);
+        if write_type == FakeWriteType::Command {
+            return Ok(());
+        }
+        result
     }
 
-    pub fn set_is_notifying(&mut self, value: bool) {
-        self.is_notifying = value;
+    make_handler_setters!();
+
+    fn handle_read(&self, offset: u16) -> Result<Vec<u8>, FakeBluetoothError> {
+        self.handlers.handle_read(self, offset, || self.get_device()?.check_gatt_access())
     }
 
-    pub fn get_flags(&self) -> Result<Vec<String>, Box<Error>> {
-        Ok(self.flags.clone())
+    fn handle_write(&self, offset: u16, value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        self.handlers.handle_write(self, offset, value, || self.get_device()?.check_gatt_access())
     }
 
-    pub fn set_flags(&mut self, flags: Vec<String>) {
-        self.flags = flags;
+    // Prepared writes only need the permission to write; as in ATT, their
+    // offsets and lengths are only checked once they are executed.
+    pub(crate) fn check_prepare_write(&self) -> Result<(), FakeBluetoothError> {
+        self.get_device()?.check_gatt_access()?;
+        if !self.has_flag(&["write", "reliable-write"]) {
+            return Err(FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Write));
+        }
+        Ok(())
     }
 
-    pub fn get_gatt_descriptors(&self) -> Result<Vec<Arc<FakeBluetoothGATTDescriptor>>, Box<Error>> {
-        Ok(self.descriptors.clone())
+    // Fails for the first queued write that could not be applied, without
+    // applying any of them. A handler only has the lengths checked up front.
+    pub(crate) fn check_prepared_writes(&self, writes: &[FakePreparedWrite]) -> Result<(), FakeBluetoothError> {
+        let max_length = *::lock(&self.max_length);
+        if self.handlers.has_write_handler() {
+            return writes.iter().try_for_each(|&(offset, ref data)| ::check_length(offset, data, max_length));
+        }
+        let mut value = self.get_value()?;
+        for &(offset, ref data) in writes {
+            value = ::write_at(&value, offset, data, max_length)?;
+        }
+        Ok(())
     }
 
-    pub fn set_gatt_descriptors(&mut self, descriptors: Vec<Arc<FakeBluetoothGATTDescriptor>>) {
-        self.descriptors = descriptors;
+    pub(crate) fn execute_prepared_writes(&self, writes: Vec<FakePreparedWrite>) -> Result<(), FakeBluetoothError> {
+        for (offset, data) in writes {
+            self.handle_write(offset, data)?;
+        }
+        Ok(())
     }
 
-    pub fn read_value(&self) -> Result<Vec<u8>, Box<Error>> {
-        self.get_value()
+    // Each call hands out a stream of its own, like a separate D-Bus client
+    // calling StartNotify; all of them see the same values in the same order.
+    pub fn start_notify(self: &Arc<Self>) -> Result<FakeNotificationStream, FakeBluetoothError> {
+        self.get_device()?.check_gatt_access()?;
+        self.check_notify_supported()?;
+        let id = NEXT_NOTIFY_SESSION_ID.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = mpsc::channel();
+        ::lock(&self.notify_subscribers).push((id, sender));
+        self.set_is_notifying(true)?;
+        Ok(FakeNotificationStream {
+            id,
+            characteristic: Arc::downgrade(self),
+            receiver,
+        })
     }
 
-    // Try some other workout if &mut self not works!
-    pub fn write_value(&mut self, value: Vec<u8>) -> Result<(), Box<Error>> {
-        self.value = value;
+    // Ends the session of `stream` alone, as StopNotify from that client
+    // would: it yields what was already sent and then finishes. Dropping the
+    // stream does the same.
+    pub fn stop_notify(&self, stream: &FakeNotificationStream) -> Result<(), FakeBluetoothError> {
+        self.get_device()?.check_gatt_access()?;
+        self.check_notify_supported()?;
+        if !self.end_notify_session(stream.id) {
+            return Err(FakeBluetoothError::Failed(String::from("No notify session started.")));
+        }
+        Ok(())
+    }
+
+    // Notifying ends with the last session.
+    fn end_notify_session(&self, id: usize) -> bool {
+        let mut subscribers = ::lock(&self.notify_subscribers);
+        let count = subscribers.len();
+        subscribers.retain(|&(subscriber_id, _)| subscriber_id != id);
+        let removed = subscribers.len() < count;
+        if removed && subscribers.is_empty() {
+            drop(subscribers);
+            let _ = self.set_is_notifying(false);
+        }
+        removed
+    }
+
+    // The peripheral side of a notification or indication. The value is
+    // stored either way, but only reaches the streams while notifying.
+    // Streams are unbounded, so this never blocks and never drops a value
+    // however far behind a reader is. Like on a real link, a notified value
+    // is cut off after MTU - 3 bytes.
+    pub fn notify_value(&self, mut value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        self.check_notify_supported()?;
+        if !*::lock(&self.is_notifying) {
+            return self.set_value(value);
+        }
+        value.truncate(self.get_device()?.get_mtu()? as usize - 3);
+        self.set_value(value.clone())?;
+        for (_, subscriber) in ::lock(&self.notify_subscribers).iter() {
+            let _ = subscriber.send(value.clone());
+        }
+        Ok(())
+    }
+
+    // Feeds notify_value() from `generator` every `interval` while notifying,
+    // replacing any generator set before.
+    pub fn set_generator(self: &Arc<Self>,
+                         generator: FakeValueGenerator,
+                         interval: Duration,
+                         clock: FakeClock)
+                         -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        self.check_notify_supported()?;
+        if interval == Duration::from_secs(0) {
+            return Err(FakeBluetoothError::InvalidArgument(String::from("The generator interval must not be zero.")));
+        }
+        let task = FakeGeneratorTask::start(self, generator, interval, clock);
+        if let Some(old_task) = ::replace(&self.generator, Some(task)) {
+            old_task.cancel();
+        }
+        Ok(())
+    }
+
+    pub fn clear_generator(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if let Some(task) = ::replace(&self.generator, None) {
+            task.cancel();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn end_notify(&self) {
+        ::lock(&self.notify_subscribers).clear();
+        let _ = self.set_is_notifying(false);
+    }
+
+    // BlueZ answers NotSupported rather than NotPermitted here.
+    fn check_notify_supported(&self) -> Result<(), FakeBluetoothError> {
+        if !self.has_flag(&["notify", "indicate"]) {
+            return Err(FakeBluetoothError::NotSupported(String::from("Notify not supported.")));
+        }
+        Ok(())
+    }
+
+    fn has_flag(&self, flags: &[&str]) -> bool {
+        ::lock(&self.flags).iter().any(|flag| flags.contains(&flag.as_str()))
+    }
+
+    pub fn add_descriptor(self: &Arc<Self>, descriptor: Arc<FakeBluetoothGATTDescriptor>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        descriptor.check_unattached()?;
+        descriptor.set_characteristic(self)?;
+        let position = ::lock(&self.descriptors).len() as u16 + 1;
+        let device = self.get_service().and_then(|service| service.get_device()).ok();
+        descriptor.update_path(&self.get_id(), device.as_deref(), position);
+        ::lock(&self.descriptors).push(descriptor.clone());
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.replace_objects(&self.get_id(), vec![], vec![descriptor.get_object()]);
+        }
+        Ok(())
+    }
+
+    pub fn remove_descriptor(&self, id: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let descriptor = {
+            let mut descriptors = ::lock(&self.descriptors);
+            match descriptors.iter().position(|descriptor| descriptor.get_id() == id) {
+                Some(index) => descriptors.remove(index),
+                None => return Err(FakeBluetoothError::NotFound(format!("No descriptor found with id: {}.", id))),
+            }
+        };
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.replace_objects(&self.get_id(), vec![descriptor.get_object()], vec![]);
+        }
+        descriptor.invalidate();
+        Ok(())
+    }
+
+    pub(crate) fn update_paths(&self, service_path: &str, device: Option<&FakeBluetoothDevice>, position: u16) {
+        let handle = device.map_or(position, |device| device.allocate_handle());
+        let path = format!("{}/char{:04x}", service_path, handle);
+        *::lock(&self.object_path) = path.clone();
+        for (index, descriptor) in ::lock(&self.descriptors).iter().enumerate() {
+            descriptor.update_path(&path, device, index as u16 + 1);
+        }
+    }
+
+    pub(crate) fn invalidate(&self) {
+        ::lock(&self.notify_subscribers).clear();
+        if let Some(task) = ::replace(&self.generator, None) {
+            task.cancel();
+        }
+        *::lock(&self.is_removed) = true;
+        *::lock(&self.service) = Weak::new();
+        for descriptor in ::lock(&self.descriptors).iter() {
+            descriptor.invalidate();
+        }
+    }
+
+    fn check_valid(&self) -> Result<(), FakeBluetoothError> {
+        if *::lock(&self.is_removed) {
+            return Err(FakeBluetoothError::NotFound(format!("The characteristic {} has been removed.", self.get_id())));
+        }
         Ok(())
     }
+
+    pub(crate) fn get_device(&self) -> Result<Arc<FakeBluetoothDevice>, FakeBluetoothError> {
+        self.get_service()?.get_device()
+    }
+
+    fn property_changed(&self, property: &str, old_value: FakePropertyValue, new_value: FakePropertyValue) {
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.emit(FakeBluetoothEvent::PropertyChanged {
+                object_path: self.get_id(),
+                property: String::from(property),
+                old_value,
+                new_value,
+            });
+        }
+    }
+
+    pub(crate) fn get_adapter(&self) -> Result<Arc<FakeBluetoothAdapter>, FakeBluetoothError> {
+        self.get_service()?.get_adapter()
+    }
+
+    pub(crate) fn get_objects(self: &Arc<Self>) -> Vec<(String, FakeBluetoothObject)> {
+        let mut objects = vec![(self.get_id(), FakeBluetoothObject::Characteristic(self.clone()))];
+        for descriptor in ::lock(&self.descriptors).iter() {
+            objects.push(descriptor.get_object());
+        }
+        objects
+    }
+}
+
+// The values notified since start_notify(), oldest first. Iterating blocks
+// until the next value arrives and ends once its session stops.
+#[derive(Debug)]
+pub struct FakeNotificationStream {
+    id: usize,
+    characteristic: Weak<FakeBluetoothGATTCharacteristic>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl FakeNotificationStream {
+    pub fn try_next_value(&self) -> Option<Vec<u8>> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn next_value_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for FakeNotificationStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.receiver.recv().ok()
+    }
+}
+
+// A client going away ends its notify session, as in BlueZ.
+impl Drop for FakeNotificationStream {
+    fn drop(&mut self) {
+        if let Some(characteristic) = self.characteristic.upgrade() {
+            characteristic.end_notify_session(self.id);
+        }
+    }
+}
+
+impl FakeAttributeValue for FakeBluetoothGATTCharacteristic {
+    fn get_value(&self) -> Result<Vec<u8>, FakeBluetoothError> {
+        FakeBluetoothGATTCharacteristic::get_value(self)
+    }
+
+    fn set_value(&self, value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        FakeBluetoothGATTCharacteristic::set_value(self, value)
+    }
+
+    fn get_max_length(&self) -> Result<usize, FakeBluetoothError> {
+        FakeBluetoothGATTCharacteristic::get_max_length(self)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FakeBluetoothGATTCharacteristicBuilder {
+    uuid: String,
+    value: Vec<u8>,
+    flags: Vec<String>,
+    max_length: Option<usize>,
+}
+
+impl FakeBluetoothGATTCharacteristicBuilder {
+    pub fn new() -> FakeBluetoothGATTCharacteristicBuilder {
+        FakeBluetoothGATTCharacteristicBuilder::default()
+    }
+
+    pub fn uuid(mut self, uuid: String) -> FakeBluetoothGATTCharacteristicBuilder {
+        self.uuid = uuid;
+        self
+    }
+
+    pub fn value(mut self, value: Vec<u8>) -> FakeBluetoothGATTCharacteristicBuilder {
+        self.value = value;
+        self
+    }
+
+    pub fn flags(mut self, flags: Vec<String>) -> FakeBluetoothGATTCharacteristicBuilder {
+        self.flags = flags;
+        self
+    }
+
+    pub fn max_length(mut self, max_length: usize) -> FakeBluetoothGATTCharacteristicBuilder {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<FakeBluetoothGATTCharacteristic>, FakeBluetoothBuildError> {
+        let uuid = ::parse_uuid(&self.uuid)?;
+        ::check_flags(&self.flags, ::CHARACTERISTIC_FLAGS)?;
+        Ok(Arc::new(FakeBluetoothGATTCharacteristic {
+            uuid: Mutex::new(uuid),
+            value: Mutex::new(self.value),
+            flags: Mutex::new(self.flags),
+            max_length: Mutex::new(self.max_length.unwrap_or(::MAX_ATTRIBUTE_LENGTH)),
+            ..FakeBluetoothGATTCharacteristic::new_empty()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fake_device::FakeBluetoothDevice;
+    use fake_error::FakeBluetoothError;
+    use fake_options::{FakeReadOptions, FakeWriteOptions};
+    use fake_test_support::{add_characteristic, connected_characteristic, pending};
+    use std::sync::{Arc, Weak};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn disconnect(device: &Weak<FakeBluetoothDevice>) {
+        device.upgrade().unwrap().disconnect().unwrap();
+    }
+
+    #[test]
+    fn read_fails_when_the_handler_disconnects() {
+        let (_adapter, device, characteristic) = connected_characteristic(&["read"]);
+        let weak_device = Arc::downgrade(&device);
+        characteristic.set_read_handler(move |_| {
+            disconnect(&weak_device);
+            Ok(vec![4, 5, 6])
+        }).unwrap();
+        assert_eq!(characteristic.read_value(), Err(FakeBluetoothError::NotConnected));
+        assert_eq!(device.is_connected(), Ok(false));
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn write_fails_when_the_handler_disconnects() {
+        let (_adapter, device, characteristic) = connected_characteristic(&["write"]);
+        let weak_device = Arc::downgrade(&device);
+        characteristic.set_write_handler(move |_, _| {
+            disconnect(&weak_device);
+            Ok(())
+        }).unwrap();
+        assert_eq!(characteristic.write_value(vec![7]), Err(FakeBluetoothError::NotConnected));
+    }
+
+    #[test]
+    fn handler_can_clear_itself() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["write"]);
+        let weak_characteristic = Arc::downgrade(&characteristic);
+        characteristic.set_write_handler(move |_, _| {
+            weak_characteristic.upgrade().unwrap().clear_write_handler().unwrap();
+            Ok(())
+        }).unwrap();
+        characteristic.write_value(vec![7]).unwrap();
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
+        characteristic.write_value(vec![8]).unwrap();
+        assert_eq!(characteristic.get_value(), Ok(vec![8]));
+    }
+
+    #[test]
+    fn concurrent_read_waits_for_the_handler() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["read"]);
+        let (entered_sender, entered) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel::<()>();
+        let mut count = 0;
+        characteristic.set_read_handler(move |_| {
+            count += 1;
+            if count == 1 {
+                entered_sender.send(()).unwrap();
+                release_receiver.recv().unwrap();
+            }
+            Ok(vec![count])
+        }).unwrap();
+        let first = {
+            let characteristic = characteristic.clone();
+            thread::spawn(move || characteristic.read_value())
+        };
+        entered.recv().unwrap();
+        let second = {
+            let characteristic = characteristic.clone();
+            thread::spawn(move || characteristic.read_value())
+        };
+        // Gives the second read time to reach the handler first.
+        thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+        assert_eq!(first.join().unwrap(), Ok(vec![1]));
+        assert_eq!(second.join().unwrap(), Ok(vec![2]));
+    }
+
+    #[test]
+    fn handlers_get_the_offset() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["read", "write"]);
+        characteristic.set_read_handler(|offset| Ok(vec![offset as u8])).unwrap();
+        let (sender, writes) = mpsc::channel();
+        characteristic.set_write_handler(move |offset, value| {
+            sender.send((offset, value)).unwrap();
+            Ok(())
+        }).unwrap();
+        assert_eq!(characteristic.read_value_with_options(FakeReadOptions::new().offset(2)), Ok(vec![2]));
+        characteristic.write_value_with_options(vec![9], FakeWriteOptions::new().offset(1)).unwrap();
+        assert_eq!(writes.try_recv(), Ok((1, vec![9])));
+    }
+
+    #[test]
+    fn handler_error_codes_come_back_as_att_errors() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["read", "write"]);
+        characteristic.set_read_handler(|_| Err(0x0e)).unwrap();
+        characteristic.set_write_handler(|_, _| Err(0x80)).unwrap();
+        assert_eq!(characteristic.read_value(), Err(FakeBluetoothError::AttError(0x0e)));
+        assert_eq!(characteristic.write_value(vec![7]), Err(FakeBluetoothError::AttError(0x80)));
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn read_at_offset_0_stores_the_handler_value() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["read"]);
+        characteristic.set_read_handler(|offset| Ok(vec![4, 5, offset as u8])).unwrap();
+        characteristic.read_value_with_options(FakeReadOptions::new().offset(1)).unwrap();
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
+        assert_eq!(characteristic.read_value(), Ok(vec![4, 5, 0]));
+        assert_eq!(characteristic.get_value(), Ok(vec![4, 5, 0]));
+    }
+
+    #[test]
+    fn handler_can_notify_another_characteristic() {
+        let (_adapter, device, characteristic) = connected_characteristic(&["write"]);
+        let other = add_characteristic(&device, &["notify"]);
+        let stream = other.start_notify().unwrap();
+        let weak_other = Arc::downgrade(&other);
+        characteristic.set_write_handler(move |_, value| {
+            weak_other.upgrade().unwrap().notify_value(value).unwrap();
+            Ok(())
+        }).unwrap();
+        characteristic.write_value(vec![7]).unwrap();
+        assert_eq!(pending(&stream), vec![vec![7]]);
+    }
+
+    #[test]
+    fn streams_see_the_same_values_in_the_same_order() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let first = characteristic.start_notify().unwrap();
+        let second = characteristic.start_notify().unwrap();
+        for value in 0..10 {
+            characteristic.notify_value(vec![value]).unwrap();
+        }
+        let expected: Vec<Vec<u8>> = (0..10).map(|value| vec![value]).collect();
+        assert_eq!(pending(&first), expected);
+        assert_eq!(pending(&second), expected);
+    }
+
+    #[test]
+    fn streams_never_drop_values() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let stream = characteristic.start_notify().unwrap();
+        for value in 0..10_000u32 {
+            characteristic.notify_value(value.to_le_bytes().to_vec()).unwrap();
+        }
+        let values = pending(&stream);
+        assert_eq!(values.len(), 10_000);
+        assert!(values.iter().enumerate().all(|(index, value)| *value == (index as u32).to_le_bytes()));
+    }
+
+    #[test]
+    fn stop_notify_ends_only_that_stream() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let first = characteristic.start_notify().unwrap();
+        let second = characteristic.start_notify().unwrap();
+        characteristic.notify_value(vec![1]).unwrap();
+        characteristic.stop_notify(&first).unwrap();
+        characteristic.notify_value(vec![2]).unwrap();
+        assert_eq!(first.collect::<Vec<_>>(), vec![vec![1]]);
+        assert_eq!(characteristic.is_notifying(), Ok(true));
+        assert_eq!(pending(&second), vec![vec![1], vec![2]]);
+        assert!(characteristic.stop_notify(&second).is_ok());
+        assert_eq!(characteristic.is_notifying(), Ok(false));
+        assert!(characteristic.stop_notify(&second).is_err());
+    }
+
+    #[test]
+    fn dropping_the_last_stream_stops_notifying() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let first = characteristic.start_notify().unwrap();
+        let second = characteristic.start_notify().unwrap();
+        drop(first);
+        assert_eq!(characteristic.is_notifying(), Ok(true));
+        drop(second);
+        assert_eq!(characteristic.is_notifying(), Ok(false));
+    }
+
+    #[test]
+    fn disconnect_ends_every_stream() {
+        let (_adapter, device, characteristic) = connected_characteristic(&["notify"]);
+        let first = characteristic.start_notify().unwrap();
+        let second = characteristic.start_notify().unwrap();
+        characteristic.notify_value(vec![1]).unwrap();
+        device.disconnect().unwrap();
+        assert_eq!(first.collect::<Vec<_>>(), vec![vec![1]]);
+        assert_eq!(second.collect::<Vec<_>>(), vec![vec![1]]);
+        assert_eq!(characteristic.is_notifying(), Ok(false));
+    }
 }
@@ -1,88 +1,251 @@
+use fake_adapter::{FakeBluetoothAdapter, FakeBluetoothObject};
 use fake_characteristic::FakeBluetoothGATTCharacteristic;
-use std::error::Error;
-use std::sync::Arc;
+use fake_device::FakeBluetoothDevice;
+use fake_error::{FakeBluetoothBuildError, FakeBluetoothError, FakeBluetoothOperation};
+use fake_event::{FakeBluetoothEvent, FakePropertyValue};
+use fake_handler::{FakeAttributeHandlers, FakeAttributeValue};
+use fake_options::{FakeReadOptions, FakeWriteOptions};
+use std::sync::{Arc, Mutex, Weak};
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct FakeBluetoothGATTDescriptor {
-    object_path: String,
-    uuid: String,
-    characteristic: Arc<FakeBluetoothGATTCharacteristic>,
-    value: Vec<u8>,
-    flags: Vec<String>,
+    object_path: Mutex<String>,
+    uuid: Mutex<String>,
+    characteristic: Mutex<Weak<FakeBluetoothGATTCharacteristic>>,
+    value: Mutex<Vec<u8>>,
+    flags: Mutex<Vec<String>>,
+    max_length: Mutex<usize>,
+    handlers: FakeAttributeHandlers,
+    is_removed: Mutex<bool>,
 }
 
 impl FakeBluetoothGATTDescriptor {
-    pub fn new(object_path: String,
-               uuid: String,
-               characteristic: Arc<FakeBluetoothGATTCharacteristic>,
-               value: Vec<u8>,
-               flags: Vec<String>)
-               -> FakeBluetoothGATTDescriptor {
+    pub fn new_empty() -> FakeBluetoothGATTDescriptor {
         FakeBluetoothGATTDescriptor {
-            object_path: object_path,
-            uuid: uuid,
-            characteristic: characteristic,
-            value: value,
-            flags: flags,
+            object_path: Mutex::new(String::new()),
+            uuid: Mutex::new(String::new()),
+            characteristic: Mutex::new(Weak::new()),
+            value: Mutex::new(vec![]),
+            flags: Mutex::new(vec![]),
+            max_length: Mutex::new(::MAX_ATTRIBUTE_LENGTH),
+            handlers: FakeAttributeHandlers::default(),
+            is_removed: Mutex::new(false),
         }
     }
 
-    pub fn new_empty() -> FakeBluetoothGATTDescriptor {
-        FakeBluetoothGATTDescriptor {
-            object_path: String::new(),
-            uuid: String::new(),
-            characteristic: Arc::new(FakeBluetoothGATTCharacteristic::new_empty()),
-            value: vec![],
-            flags: vec![],
+    pub fn get_id(&self) -> String {
+        ::lock(&self.object_path).clone()
+    }
+
+    pub fn set_id(&self, path: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let old_path = ::replace(&self.object_path, path.clone());
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.rename_object(&old_path, path);
         }
+        Ok(())
     }
 
-    pub fn get_id(&self) -> String {
-        self.object_path.clone()
+    make_getter!(get_uuid, uuid, String);
+
+    make_setter!(set_uuid, uuid, String, "UUID", ::parse_uuid);
+
+    pub fn get_characteristic(&self) -> Result<Arc<FakeBluetoothGATTCharacteristic>, FakeBluetoothError> {
+        self.check_valid()?;
+        match ::lock(&self.characteristic).upgrade() {
+            Some(characteristic) => Ok(characteristic),
+            None => Err(FakeBluetoothError::NotFound(String::from("No characteristic found."))),
+        }
     }
 
-    pub fn set_id(&mut self, path: String) {
-        self.object_path = path;
+    pub(crate) fn check_unattached(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if ::lock(&self.characteristic).upgrade().is_some() {
+            return Err(FakeBluetoothError::AlreadyExists(format!("Descriptor {} already belongs to a characteristic.", self.get_id())));
+        }
+        Ok(())
     }
 
-    pub fn get_uuid(&self) -> Result<String, Box<Error>> {
-        Ok(self.uuid.clone())
+    pub fn set_characteristic(&self, characteristic: &Arc<FakeBluetoothGATTCharacteristic>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        *::lock(&self.characteristic) = Arc::downgrade(characteristic);
+        Ok(())
     }
 
-    pub fn set_uuid(&mut self, uuid: String) {
-        self.uuid = uuid;
+    make_getter!(get_value, value, Vec<u8>);
+
+    make_setter!(set_value, value, Vec<u8>, "Value");
+
+    make_getter!(get_flags, flags, Vec<String>);
+
+    make_setter!(set_flags, flags, Vec<String>, "Flags", ::parse_descriptor_flags);
+
+    make_getter!(get_max_length, max_length, usize);
+
+    make_setter!(set_max_length, max_length, usize);
+
+    pub fn read_value(&self) -> Result<Vec<u8>, FakeBluetoothError> {
+        self.read_value_with_options(FakeReadOptions::new())
     }
 
-    pub fn get_characteristic(&self) -> Result<Arc<FakeBluetoothGATTCharacteristic>, Box<Error>> {
-        Ok(self.characteristic.clone())
+    pub fn read_value_with_options(&self, options: FakeReadOptions) -> Result<Vec<u8>, FakeBluetoothError> {
+        self.check_connected()?;
+        if !self.has_flag("read") {
+            return Err(FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Read));
+        }
+        self.handle_read(options.get_offset())
     }
 
-    pub fn set_characteristic(&mut self, characteristic: Arc<FakeBluetoothGATTCharacteristic>) {
-        self.characteristic = characteristic;
+    pub fn write_value(&self, value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        self.write_value_with_options(value, FakeWriteOptions::new())
     }
 
-    pub fn get_value(&self) -> Result<Vec<u8>, Box<Error>> {
-        Ok(self.value.clone())
+    // Descriptors are always written with a response, whatever the type.
+    pub fn write_value_with_options(&self, value: Vec<u8>, options: FakeWriteOptions) -> Result<(), FakeBluetoothError> {
+        self.check_connected()?;
+        if !self.has_flag("write") {
+            return Err(FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Write));
+        }
+        self.handle_write(options.get_offset(), value)
     }
 
-    pub fn set_value(&mut self, value: Vec<u8>) {
-        self.value = value;
+    make_handler_setters!();
+
+    fn handle_read(&self, offset: u16) -> Result<Vec<u8>, FakeBluetoothError> {
+        self.handlers.handle_read(self, offset, || self.check_connected())
     }
 
-    pub fn get_flags(&self) -> Result<Vec<String>, Box<Error>> {
-        Ok(self.flags.clone())
+    fn handle_write(&self, offset: u16, value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        self.handlers.handle_write(self, offset, value, || self.check_connected())
     }
 
-    pub fn set_flags(&mut self, flags: Vec<String>) {
-        self.flags = flags;
+    fn check_connected(&self) -> Result<(), FakeBluetoothError> {
+        self.get_characteristic()?.get_device()?.check_gatt_access()
     }
 
-    pub fn read_value(&self) -> Result<Vec<u8>, Box<Error>> {
-        self.get_value()
+    fn has_flag(&self, flag: &str) -> bool {
+        ::lock(&self.flags).iter().any(|descriptor_flag| descriptor_flag == flag)
     }
 
-    pub fn write_value(&mut self, value: Vec<u8>) -> Result<(), Box<Error>> {
-        self.value = value;
+    pub(crate) fn update_path(&self, characteristic_path: &str, device: Option<&FakeBluetoothDevice>, position: u16) {
+        let handle = device.map_or(position, |device| device.allocate_handle());
+        *::lock(&self.object_path) = format!("{}/desc{:04x}", characteristic_path, handle);
+    }
+
+    pub(crate) fn invalidate(&self) {
+        *::lock(&self.is_removed) = true;
+        *::lock(&self.characteristic) = Weak::new();
+    }
+
+    fn check_valid(&self) -> Result<(), FakeBluetoothError> {
+        if *::lock(&self.is_removed) {
+            return Err(FakeBluetoothError::NotFound(format!("The descriptor {} has been removed.", self.get_id())));
+        }
         Ok(())
     }
+
+    fn property_changed(&self, property: &str, old_value: FakePropertyValue, new_value: FakePropertyValue) {
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.emit(FakeBluetoothEvent::PropertyChanged {
+                object_path: self.get_id(),
+                property: String::from(property),
+                old_value,
+                new_value,
+            });
+        }
+    }
+
+    pub(crate) fn get_adapter(&self) -> Result<Arc<FakeBluetoothAdapter>, FakeBluetoothError> {
+        self.get_characteristic()?.get_adapter()
+    }
+
+    pub(crate) fn get_object(self: &Arc<Self>) -> (String, FakeBluetoothObject) {
+        (self.get_id(), FakeBluetoothObject::Descriptor(self.clone()))
+    }
+}
+
+impl FakeAttributeValue for FakeBluetoothGATTDescriptor {
+    fn get_value(&self) -> Result<Vec<u8>, FakeBluetoothError> {
+        FakeBluetoothGATTDescriptor::get_value(self)
+    }
+
+    fn set_value(&self, value: Vec<u8>) -> Result<(), FakeBluetoothError> {
+        FakeBluetoothGATTDescriptor::set_value(self, value)
+    }
+
+    fn get_max_length(&self) -> Result<usize, FakeBluetoothError> {
+        FakeBluetoothGATTDescriptor::get_max_length(self)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FakeBluetoothGATTDescriptorBuilder {
+    uuid: String,
+    value: Vec<u8>,
+    flags: Vec<String>,
+    max_length: Option<usize>,
+}
+
+impl FakeBluetoothGATTDescriptorBuilder {
+    pub fn new() -> FakeBluetoothGATTDescriptorBuilder {
+        FakeBluetoothGATTDescriptorBuilder::default()
+    }
+
+    pub fn uuid(mut self, uuid: String) -> FakeBluetoothGATTDescriptorBuilder {
+        self.uuid = uuid;
+        self
+    }
+
+    pub fn value(mut self, value: Vec<u8>) -> FakeBluetoothGATTDescriptorBuilder {
+        self.value = value;
+        self
+    }
+
+    pub fn flags(mut self, flags: Vec<String>) -> FakeBluetoothGATTDescriptorBuilder {
+        self.flags = flags;
+        self
+    }
+
+    pub fn max_length(mut self, max_length: usize) -> FakeBluetoothGATTDescriptorBuilder {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<FakeBluetoothGATTDescriptor>, FakeBluetoothBuildError> {
+        let uuid = ::parse_uuid(&self.uuid)?;
+        ::check_flags(&self.flags, ::DESCRIPTOR_FLAGS)?;
+        Ok(Arc::new(FakeBluetoothGATTDescriptor {
+            uuid: Mutex::new(uuid),
+            value: Mutex::new(self.value),
+            flags: Mutex::new(self.flags),
+            max_length: Mutex::new(self.max_length.unwrap_or(::MAX_ATTRIBUTE_LENGTH)),
+            ..FakeBluetoothGATTDescriptor::new_empty()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FakeBluetoothGATTDescriptorBuilder;
+    use fake_error::FakeBluetoothError;
+    use fake_test_support::connected_characteristic;
+    use std::sync::Arc;
+
+    #[test]
+    fn read_fails_when_the_handler_disconnects() {
+        let (_adapter, device, characteristic) = connected_characteristic(&[]);
+        let descriptor = FakeBluetoothGATTDescriptorBuilder::new()
+            .uuid(String::from("2902"))
+            .flags(vec![String::from("read")])
+            .build()
+            .unwrap();
+        characteristic.add_descriptor(descriptor.clone()).unwrap();
+
+        let weak_device = Arc::downgrade(&device);
+        descriptor.set_read_handler(move |_| {
+            weak_device.upgrade().unwrap().disconnect().unwrap();
+            Ok(vec![1, 0])
+        }).unwrap();
+        assert_eq!(descriptor.read_value(), Err(FakeBluetoothError::NotConnected));
+    }
 }
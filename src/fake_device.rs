@@ -1,18 +1,799 @@
-use fake_adapter::FakeBluetoothAdapter;
+use fake_adapter::{FakeBluetoothAdapter, FakeBluetoothObject};
+use fake_agent::{FakeAgent, FakeAgentError, FakeIoCapability, FakePairingMethod};
+use fake_characteristic::{FakeBluetoothGATTCharacteristic, FakePreparedWrite};
+use fake_discovery_session::FakeDiscoveryFilter;
+use fake_error::{FakeBluetoothBuildError, FakeBluetoothError};
+use fake_event::{FakeBluetoothEvent, FakePropertyValue};
 use fake_service::FakeBluetoothGATTService;
-use std::error::Error;
-use std::sync::Arc;
-use rustc_serialize::hex::FromHex;
+use modalias::Modalias;
+use std::ptr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
-#[derive(Clone, Debug)]
+// The writes a reliable write has prepared so far, in order.
+type FakeWriteQueue = Vec<(Arc<FakeBluetoothGATTCharacteristic>, FakePreparedWrite)>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FakeGattPolicy {
+    // GATT access ignores the connection state entirely.
+    Always,
+    // GATT access needs a connection; services resolve as part of connect().
+    #[default]
+    AfterConnect,
+    // GATT access needs a connection and an explicit resolve_services() call.
+    AfterServiceDiscovery,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FakePairingState {
+    Idle,
+    Pairing,
+    Canceled,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FakeDiscoverySchedule {
+    // Listed by the adapter from the start, whether or not anyone discovers.
+    #[default]
+    Immediately,
+    // Found on the given get_device_list() call of a running discovery.
+    AfterPolls(u32),
+    // Found once discovery has been running for the given time.
+    AfterDelay(Duration),
+    // Found as soon as discovery starts and lost again when it stops.
+    WhileDiscovering,
+}
+
+#[derive(Debug)]
 pub struct FakeBluetoothDevice {
-    object_path: String,
-    adapter: Arc<FakeBluetoothAdapter>,
+    object_path: Mutex<String>,
+    adapter: Mutex<Weak<FakeBluetoothAdapter>>,
+    address: Mutex<String>,
+    appearance: Mutex<u16>,
+    class: Mutex<u32>,
+    gatt_services: Mutex<Vec<Arc<FakeBluetoothGATTService>>>,
+    is_paired: Mutex<bool>,
+    is_bonded: Mutex<bool>,
+    io_capability: Mutex<FakeIoCapability>,
+    passkey: Mutex<u32>,
+    pin_code: Mutex<String>,
+    pairing_state: Mutex<FakePairingState>,
+    is_connectable: Mutex<bool>,
+    is_connected: Mutex<bool>,
+    is_trusted: Mutex<bool>,
+    is_blocked: Mutex<bool>,
+    is_legacy_pairing: Mutex<bool>,
+    uuids: Mutex<Vec<String>>,
+    name: Mutex<String>,
+    icon: Mutex<String>,
+    alias: Mutex<String>,
+    product_version: Mutex<u32>,
+    rssi: Mutex<i16>,
+    tx_power: Mutex<i16>,
+    modalias: Mutex<String>,
+    gatt_policy: Mutex<FakeGattPolicy>,
+    is_services_resolved: Mutex<bool>,
+    mtu: Mutex<u16>,
+    max_mtu: Mutex<u16>,
+    prepared_writes: Mutex<Option<FakeWriteQueue>>,
+    discovery_schedule: Mutex<FakeDiscoverySchedule>,
+    disappears_on_stop: Mutex<bool>,
+    is_discovered: Mutex<bool>,
+    next_handle: Mutex<u16>,
+    is_removed: Mutex<bool>,
+}
+
+impl FakeBluetoothDevice {
+    pub fn new_empty() -> FakeBluetoothDevice {
+        FakeBluetoothDevice {
+            object_path: Mutex::new(String::new()),
+            adapter: Mutex::new(Weak::new()),
+            address: Mutex::new(String::new()),
+            appearance: Mutex::new(0),
+            class: Mutex::new(0),
+            gatt_services: Mutex::new(vec![]),
+            is_paired: Mutex::new(false),
+            is_bonded: Mutex::new(false),
+            io_capability: Mutex::new(FakeIoCapability::default()),
+            passkey: Mutex::new(0),
+            pin_code: Mutex::new(String::new()),
+            pairing_state: Mutex::new(FakePairingState::Idle),
+            is_connectable: Mutex::new(false),
+            is_connected: Mutex::new(false),
+            is_trusted: Mutex::new(false),
+            is_blocked: Mutex::new(false),
+            is_legacy_pairing: Mutex::new(false),
+            uuids: Mutex::new(vec![]),
+            name: Mutex::new(String::new()),
+            icon: Mutex::new(String::new()),
+            alias: Mutex::new(String::new()),
+            product_version: Mutex::new(0),
+            rssi: Mutex::new(0),
+            tx_power: Mutex::new(0),
+            modalias: Mutex::new(String::new()),
+            gatt_policy: Mutex::new(FakeGattPolicy::default()),
+            is_services_resolved: Mutex::new(false),
+            mtu: Mutex::new(::DEFAULT_MTU),
+            max_mtu: Mutex::new(::MAX_MTU),
+            prepared_writes: Mutex::new(None),
+            discovery_schedule: Mutex::new(FakeDiscoverySchedule::default()),
+            disappears_on_stop: Mutex::new(false),
+            is_discovered: Mutex::new(true),
+            next_handle: Mutex::new(1),
+            is_removed: Mutex::new(false),
+        }
+    }
+
+    pub fn get_id(&self) -> String {
+        ::lock(&self.object_path).clone()
+    }
+
+    pub fn set_id(&self, object_path: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let old_path = ::replace(&self.object_path, object_path.clone());
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.rename_object(&old_path, object_path);
+        }
+        Ok(())
+    }
+
+    pub fn get_adapter(&self) -> Result<Arc<FakeBluetoothAdapter>, FakeBluetoothError> {
+        self.check_valid()?;
+        match ::lock(&self.adapter).upgrade() {
+            Some(adapter) => Ok(adapter),
+            None => Err(FakeBluetoothError::NotFound(String::from("No adapter found."))),
+        }
+    }
+
+    pub(crate) fn check_unattached(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if ::lock(&self.adapter).upgrade().is_some() {
+            return Err(FakeBluetoothError::AlreadyExists(format!("Device {} already belongs to an adapter.", self.get_address()?)));
+        }
+        Ok(())
+    }
+
+    pub fn set_adapter(&self, adapter: &Arc<FakeBluetoothAdapter>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        *::lock(&self.adapter) = Arc::downgrade(adapter);
+        Ok(())
+    }
+
+    make_getter!(get_address, address, String);
+
+    make_setter!(set_address, address, String, "Address", ::parse_address);
+
+    make_getter!(get_name, name, String);
+
+    make_setter!(set_name, name, String, "Name");
+
+    make_getter!(get_icon, icon, String);
+
+    make_setter!(set_icon, icon, String, "Icon");
+
+    make_getter!(get_class, class, u32);
+
+    make_setter!(set_class, class, u32, "Class");
+
+    make_getter!(get_appearance, appearance, u16);
+
+    make_setter!(set_appearance, appearance, u16, "Appearance");
+
+    make_getter!(get_uuids, uuids, Vec<String>);
+
+    make_setter!(set_uuids, uuids, Vec<String>, "UUIDs", ::parse_uuids);
+
+    make_getter!(is_paired, is_paired, bool);
+
+    make_setter!(set_paired, is_paired, bool, "Paired");
+
+    make_getter!(is_bonded, is_bonded, bool);
+
+    make_setter!(set_bonded, is_bonded, bool, "Bonded");
+
+    make_getter!(get_io_capability, io_capability, FakeIoCapability);
+
+    make_setter!(set_io_capability, io_capability, FakeIoCapability);
+
+    // The passkey the device displays, or expects to be entered.
+    make_getter!(get_passkey, passkey, u32);
+
+    make_setter!(set_passkey, passkey, u32);
+
+    // The PIN code legacy pairing expects.
+    make_getter!(get_pin_code, pin_code, String);
+
+    make_setter!(set_pin_code, pin_code, String);
+
+    // How pair() would go with the current default agent; without an agent
+    // there is nobody to ask, so just works is the only way.
+    pub fn get_pairing_method(&self) -> Result<FakePairingMethod, FakeBluetoothError> {
+        self.check_valid()?;
+        let capability = match self.get_adapter()?.get_agent_manager().get_agent() {
+            Some((_, capability)) => capability,
+            None => FakeIoCapability::NoInputNoOutput,
+        };
+        Ok(capability.pairing_method(*::lock(&self.io_capability)))
+    }
+
+    // Connects if needed, then pairs through the default agent of the
+    // adapter's agent manager, using PIN codes for legacy pairing and
+    // otherwise the method both sides' IO capabilities call for. The device
+    // is only paired and bonded on success.
+    pub fn pair(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if *::lock(&self.is_paired) {
+            return Err(FakeBluetoothError::AlreadyExists(String::from("Already paired.")));
+        }
+        {
+            let mut pairing_state = ::lock(&self.pairing_state);
+            if *pairing_state != FakePairingState::Idle {
+                return Err(FakeBluetoothError::InProgress(String::from("Pairing in progress.")));
+            }
+            *pairing_state = FakePairingState::Pairing;
+        }
+        let result = self.run_pairing();
+        *::lock(&self.pairing_state) = FakePairingState::Idle;
+        if result.is_ok() {
+            self.set_paired(true)?;
+            self.set_bonded(true)?;
+        }
+        result
+    }
+
+    // Meant to be called while pair() waits on the agent, e.g. from another
+    // thread or from the agent itself.
+    pub fn cancel_pairing(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let mut pairing_state = ::lock(&self.pairing_state);
+        if *pairing_state != FakePairingState::Pairing {
+            return Err(FakeBluetoothError::NotFound(String::from("No pairing in progress.")));
+        }
+        *pairing_state = FakePairingState::Canceled;
+        Ok(())
+    }
+
+    // Drops the link along with everything that was stored about the device.
+    pub(crate) fn forget(&self) {
+        {
+            let mut pairing_state = ::lock(&self.pairing_state);
+            if *pairing_state == FakePairingState::Pairing {
+                *pairing_state = FakePairingState::Canceled;
+            }
+        }
+        self.drop_connection();
+        let _ = self.set_paired(false);
+        let _ = self.set_bonded(false);
+        let _ = self.set_trusted(false);
+    }
+
+    fn run_pairing(&self) -> Result<(), FakeBluetoothError> {
+        if !*::lock(&self.is_connected) {
+            self.connect()?;
+        }
+        let is_legacy_pairing = *::lock(&self.is_legacy_pairing);
+        let (agent, capability) = match self.get_adapter()?.get_agent_manager().get_agent() {
+            Some(agent) => agent,
+            None if is_legacy_pairing => return Err(FakeBluetoothError::AuthenticationFailed),
+            None => return Ok(()),
+        };
+        let id = self.get_id();
+        let device_capability = *::lock(&self.io_capability);
+        let passkey = *::lock(&self.passkey);
+        let reply = if is_legacy_pairing {
+            let pin_code = ::lock(&self.pin_code).clone();
+            agent.request_pin_code(&id).map(|reply| reply == pin_code)
+        } else {
+            match capability.pairing_method(device_capability) {
+                FakePairingMethod::JustWorks => Ok(true),
+                FakePairingMethod::NumericComparison => agent.request_confirmation(&id, passkey).map(|_| true),
+                FakePairingMethod::PasskeyEntry if capability.enters_passkey(device_capability) => {
+                    agent.request_passkey(&id).map(|reply| reply == passkey)
+                },
+                FakePairingMethod::PasskeyEntry => {
+                    agent.display_passkey(&id, passkey);
+                    Ok(true)
+                },
+            }
+        };
+        if *::lock(&self.pairing_state) == FakePairingState::Canceled {
+            agent.cancel();
+            return Err(FakeBluetoothError::AuthenticationCanceled);
+        }
+        match reply {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(FakeBluetoothError::AuthenticationFailed),
+            Err(FakeAgentError::Rejected) => Err(FakeBluetoothError::AuthenticationRejected),
+            Err(FakeAgentError::Canceled) => Err(FakeBluetoothError::AuthenticationCanceled),
+            Err(FakeAgentError::TimedOut) => Err(FakeBluetoothError::AuthenticationTimeout),
+        }
+    }
+
+    make_getter!(is_connectable, is_connectable, bool);
+
+    make_setter!(set_connectable, is_connectable, bool);
+
+    make_getter!(is_connected, is_connected, bool);
+
+    pub fn set_connected(&self, connected: bool) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if connected {
+            self.check_powered()?;
+            self.check_not_blocked()?;
+            if ::update(&self.is_connected, true).is_some() {
+                self.emit(FakeBluetoothEvent::Connected(self.get_id()));
+            }
+            if *::lock(&self.gatt_policy) != FakeGattPolicy::AfterServiceDiscovery {
+                self.update_services_resolved(true);
+            }
+        } else {
+            self.drop_connection();
+        }
+        Ok(())
+    }
+
+    make_getter!(get_gatt_policy, gatt_policy, FakeGattPolicy);
+
+    make_setter!(set_gatt_policy, gatt_policy, FakeGattPolicy);
+
+    make_getter!(is_services_resolved, is_services_resolved, bool);
+
+    pub fn resolve_services(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if !*::lock(&self.is_connected) {
+            return Err(FakeBluetoothError::NotConnected);
+        }
+        self.update_services_resolved(true);
+        Ok(())
+    }
+
+    make_getter!(get_mtu, mtu, u16);
+
+    make_getter!(get_max_mtu, max_mtu, u16);
+
+    // Takes effect on the next exchange_mtu().
+    pub fn set_max_mtu(&self, max_mtu: u16) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if max_mtu < ::DEFAULT_MTU {
+            return Err(FakeBluetoothError::InvalidArgument(format!("The MTU can not be less than {}.", ::DEFAULT_MTU)));
+        }
+        *::lock(&self.max_mtu) = max_mtu;
+        Ok(())
+    }
+
+    // The ATT MTU exchange: both sides settle on the smaller of the two
+    // MTUs, but never go below the default. The MTU resets on disconnect.
+    pub fn exchange_mtu(&self, mtu: u16) -> Result<u16, FakeBluetoothError> {
+        self.check_valid()?;
+        if !*::lock(&self.is_connected) {
+            return Err(FakeBluetoothError::NotConnected);
+        }
+        let mtu = mtu.clamp(::DEFAULT_MTU, *::lock(&self.max_mtu));
+        *::lock(&self.mtu) = mtu;
+        Ok(mtu)
+    }
+
+    // A reliable write queues prepared writes to any of the device's
+    // characteristics; none of them show until the whole queue is executed.
+    pub fn begin_reliable_write(&self) -> Result<(), FakeBluetoothError> {
+        self.check_gatt_access()?;
+        let mut prepared_writes = ::lock(&self.prepared_writes);
+        if prepared_writes.is_some() {
+            return Err(FakeBluetoothError::InProgress(String::from("Reliable write already in progress.")));
+        }
+        *prepared_writes = Some(vec![]);
+        Ok(())
+    }
+
+    pub fn prepare_write(&self,
+                         characteristic: &Arc<FakeBluetoothGATTCharacteristic>,
+                         offset: u16,
+                         value: Vec<u8>)
+                         -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if !ptr::eq(&*characteristic.get_device()?, self) {
+            return Err(FakeBluetoothError::InvalidArgument(format!("The characteristic {} belongs to another device.",
+                                                                   characteristic.get_id())));
+        }
+        characteristic.check_prepare_write()?;
+        match *::lock(&self.prepared_writes) {
+            Some(ref mut prepared_writes) => prepared_writes.push((characteristic.clone(), (offset, value))),
+            None => return Err(FakeBluetoothError::Failed(String::from("No reliable write in progress."))),
+        }
+        Ok(())
+    }
+
+    // Writes are applied in the order they were prepared, and only once every
+    // one of them turned out to be valid. The transaction ends either way.
+    pub fn execute_reliable_write(&self) -> Result<(), FakeBluetoothError> {
+        self.check_gatt_access()?;
+        let prepared_writes = match ::lock(&self.prepared_writes).take() {
+            Some(prepared_writes) => prepared_writes,
+            None => return Err(FakeBluetoothError::Failed(String::from("No reliable write in progress."))),
+        };
+        let mut writes: Vec<(Arc<FakeBluetoothGATTCharacteristic>, Vec<FakePreparedWrite>)> = vec![];
+        for (characteristic, write) in prepared_writes {
+            match writes.iter().position(|(queued, _)| Arc::ptr_eq(queued, &characteristic)) {
+                Some(index) => writes[index].1.push(write),
+                None => writes.push((characteristic, vec![write])),
+            }
+        }
+        for (characteristic, characteristic_writes) in &writes {
+            characteristic.check_prepared_writes(characteristic_writes)?;
+        }
+        for (characteristic, characteristic_writes) in writes {
+            characteristic.execute_prepared_writes(characteristic_writes)?;
+        }
+        Ok(())
+    }
+
+    pub fn abort_reliable_write(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        match ::lock(&self.prepared_writes).take() {
+            Some(_) => Ok(()),
+            None => Err(FakeBluetoothError::Failed(String::from("No reliable write in progress."))),
+        }
+    }
+
+    make_getter!(is_trusted, is_trusted, bool);
+
+    make_setter!(set_trusted, is_trusted, bool, "Trusted");
+
+    make_getter!(is_blocked, is_blocked, bool);
+
+    // Blocking a connected device drops the connection right away.
+    pub fn set_blocked(&self, blocked: bool) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if ::update(&self.is_blocked, blocked).is_some() {
+            self.property_changed("Blocked", (!blocked).into(), blocked.into());
+        }
+        if blocked {
+            self.drop_connection();
+        }
+        Ok(())
+    }
+
+    make_getter!(get_alias, alias, String);
+
+    make_setter!(set_alias, alias, String, "Alias");
+
+    make_getter!(is_legacy_pairing, is_legacy_pairing, bool);
+
+    make_setter!(set_legacy_pairing, is_legacy_pairing, bool, "LegacyPairing");
+
+    make_getter!(get_product_version, product_version, u32);
+
+    make_setter!(set_product_version, product_version, u32);
+
+    pub fn get_modalias(&self) -> Result<Modalias, FakeBluetoothError> {
+        self.check_valid()?;
+        let modalias = ::lock(&self.modalias).clone();
+        if modalias.is_empty() {
+            return Err(FakeBluetoothError::NotFound(String::from("No modalias found.")));
+        }
+        modalias.parse()
+    }
+
+    make_setter!(set_modalias, modalias, String, "Modalias", ::parse_modalias);
+
+    pub fn get_vendor_id_source(&self) -> Result<String, FakeBluetoothError> {
+        Ok(self.get_modalias()?.get_source())
+    }
+
+    pub fn get_vendor_id(&self) -> Result<u32, FakeBluetoothError> {
+        match self.get_modalias()?.get_vendor() {
+            Some(vendor_id) => Ok(vendor_id as u32),
+            None => Err(FakeBluetoothError::NotFound(String::from("No vendor id found."))),
+        }
+    }
+
+    pub fn get_product_id(&self) -> Result<u32, FakeBluetoothError> {
+        match self.get_modalias()?.get_product() {
+            Some(product_id) => Ok(product_id as u32),
+            None => Err(FakeBluetoothError::NotFound(String::from("No product id found."))),
+        }
+    }
+
+    pub fn get_device_id(&self) -> Result<u32, FakeBluetoothError> {
+        match self.get_modalias()?.get_device() {
+            Some(device_id) => Ok(device_id as u32),
+            None => Err(FakeBluetoothError::NotFound(String::from("No device id found."))),
+        }
+    }
+
+    make_getter!(get_rssi, rssi, i16);
+
+    make_setter!(set_rssi, rssi, i16, "RSSI");
+
+    make_getter!(get_tx_power, tx_power, i16);
+
+    make_setter!(set_tx_power, tx_power, i16, "TxPower");
+
+    make_getter!(get_discovery_schedule, discovery_schedule, FakeDiscoverySchedule);
+
+    pub fn set_discovery_schedule(&self, schedule: FakeDiscoverySchedule) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        *::lock(&self.discovery_schedule) = schedule;
+        self.update_discovered_state(schedule == FakeDiscoverySchedule::Immediately);
+        Ok(())
+    }
+
+    make_getter!(disappears_on_stop, disappears_on_stop, bool);
+
+    make_setter!(set_disappears_on_stop, disappears_on_stop, bool);
+
+    pub(crate) fn is_discovered(&self) -> bool {
+        *::lock(&self.is_discovered)
+    }
+
+    // `discovery` holds the polls and time since discovery started, if it is running.
+    pub(crate) fn update_discovered(&self, discovery: Option<(u32, Duration)>, filter: Option<&FakeDiscoveryFilter>) {
+        if let (false, Some((polls, elapsed))) = (self.is_discovered(), discovery) {
+            if !filter.is_none_or(|filter| filter.matches(self)) {
+                return;
+            }
+            let discovered = match *::lock(&self.discovery_schedule) {
+                FakeDiscoverySchedule::Immediately | FakeDiscoverySchedule::WhileDiscovering => true,
+                FakeDiscoverySchedule::AfterPolls(count) => polls >= count,
+                FakeDiscoverySchedule::AfterDelay(delay) => elapsed >= delay,
+            };
+            self.update_discovered_state(discovered);
+        }
+    }
+
+    pub(crate) fn discovery_stopped(&self) {
+        if *::lock(&self.disappears_on_stop) ||
+           *::lock(&self.discovery_schedule) == FakeDiscoverySchedule::WhileDiscovering {
+            self.update_discovered_state(false);
+        }
+    }
+
+    fn update_discovered_state(&self, discovered: bool) {
+        if ::update(&self.is_discovered, discovered).is_some() {
+            self.emit(if discovered {
+                FakeBluetoothEvent::DeviceAdded(self.get_id())
+            } else {
+                FakeBluetoothEvent::DeviceRemoved(self.get_id())
+            });
+        }
+    }
+
+    pub fn get_gatt_services(&self) -> Result<Vec<Arc<FakeBluetoothGATTService>>, FakeBluetoothError> {
+        self.check_valid()?;
+        if !self.is_gatt_visible() {
+            return Ok(vec![]);
+        }
+        Ok(::lock(&self.gatt_services).clone())
+    }
+
+    // Works like remove_service() and add_service() for whatever changed,
+    // once every new service is known to be addable.
+    pub fn set_gatt_service(self: &Arc<Self>, services: Vec<Arc<FakeBluetoothGATTService>>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let (removed, added) = ::diff_children(&::lock(&self.gatt_services), &services)?;
+        added.iter().try_for_each(|service| service.check_unattached())?;
+        for service in removed {
+            self.remove_service(service.get_id())?;
+        }
+        for service in added {
+            self.add_service(service)?;
+        }
+        *::lock(&self.gatt_services) = services;
+        Ok(())
+    }
+
+    pub fn add_service(self: &Arc<Self>, service: Arc<FakeBluetoothGATTService>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        service.check_unattached()?;
+        service.set_device(self)?;
+        service.update_paths(&self.get_id(), Some(self), 0);
+        ::lock(&self.gatt_services).push(service.clone());
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.replace_objects(&self.get_id(), vec![], service.get_objects());
+        }
+        Ok(())
+    }
+
+    pub fn remove_service(&self, id: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let service = {
+            let mut services = ::lock(&self.gatt_services);
+            match services.iter().position(|service| service.get_id() == id) {
+                Some(index) => services.remove(index),
+                None => return Err(FakeBluetoothError::NotFound(format!("No service found with id: {}.", id))),
+            }
+        };
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.replace_objects(&self.get_id(), service.get_objects(), vec![]);
+        }
+        service.invalidate();
+        Ok(())
+    }
+
+    pub(crate) fn allocate_handle(&self) -> u16 {
+        let mut next_handle = ::lock(&self.next_handle);
+        let handle = *next_handle;
+        *next_handle += 1;
+        handle
+    }
+
+    pub(crate) fn update_paths(&self, path: String) {
+        *::lock(&self.object_path) = path.clone();
+        *::lock(&self.next_handle) = 1;
+        for service in ::lock(&self.gatt_services).iter() {
+            service.update_paths(&path, Some(self), 0);
+        }
+    }
+
+    pub(crate) fn invalidate(&self) {
+        *::lock(&self.is_removed) = true;
+        *::lock(&self.adapter) = Weak::new();
+        for service in ::lock(&self.gatt_services).iter() {
+            service.invalidate();
+        }
+    }
+
+    fn check_valid(&self) -> Result<(), FakeBluetoothError> {
+        if *::lock(&self.is_removed) {
+            return Err(FakeBluetoothError::NotFound(format!("The device {} has been removed.", self.get_id())));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get_objects(self: &Arc<Self>) -> Vec<(String, FakeBluetoothObject)> {
+        let mut objects = vec![(self.get_id(), FakeBluetoothObject::Device(self.clone()))];
+        for service in ::lock(&self.gatt_services).iter() {
+            objects.extend(service.get_objects());
+        }
+        objects
+    }
+
+    pub fn connect(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        self.check_powered()?;
+        self.check_not_blocked()?;
+        if !*::lock(&self.is_connectable) {
+            return Err(FakeBluetoothError::NotConnectable);
+        }
+        if *::lock(&self.is_connected) {
+            return Err(FakeBluetoothError::AlreadyConnected);
+        }
+        self.set_connected(true)
+    }
+
+    fn check_not_blocked(&self) -> Result<(), FakeBluetoothError> {
+        if *::lock(&self.is_blocked) {
+            return Err(FakeBluetoothError::Failed(String::from("The device is blocked.")));
+        }
+        Ok(())
+    }
+
+    // The device connecting to us, which an untrusted device may only do
+    // once the agent authorizes it.
+    pub fn connect_incoming(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        self.check_powered()?;
+        self.check_not_blocked()?;
+        if *::lock(&self.is_connected) {
+            return Err(FakeBluetoothError::AlreadyConnected);
+        }
+        self.authorize(|agent, id| agent.request_authorization(id))?;
+        self.set_connected(true)
+    }
+
+    // The device using one of our services, as a profile connection would.
+    pub fn access_service(&self, uuid: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let uuid = ::parse_uuid(&uuid)?;
+        if !*::lock(&self.is_connected) {
+            return Err(FakeBluetoothError::NotConnected);
+        }
+        self.authorize(|agent, id| agent.authorize_service(id, &uuid))
+    }
+
+    // Trusted devices need no authorization; for anyone else the default
+    // agent decides, and without one the request is refused.
+    fn authorize<F>(&self, request: F) -> Result<(), FakeBluetoothError>
+        where F: FnOnce(&dyn FakeAgent, &str) -> Result<(), FakeAgentError>
+    {
+        if *::lock(&self.is_trusted) {
+            return Ok(());
+        }
+        let agent = match self.get_adapter()?.get_agent_manager().get_agent() {
+            Some((agent, _)) => agent,
+            None => return Err(FakeBluetoothError::NotAuthorized),
+        };
+        match request(&*agent, &self.get_id()) {
+            Ok(()) => Ok(()),
+            Err(FakeAgentError::Rejected) => Err(FakeBluetoothError::NotAuthorized),
+            Err(FakeAgentError::Canceled) => Err(FakeBluetoothError::AuthenticationCanceled),
+            Err(FakeAgentError::TimedOut) => Err(FakeBluetoothError::AuthenticationTimeout),
+        }
+    }
+
+    pub fn disconnect(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if !*::lock(&self.is_connected) {
+            return Err(FakeBluetoothError::NotConnected);
+        }
+        self.drop_connection();
+        Ok(())
+    }
+
+    // Like a real link loss: the services go away with the connection and
+    // every notification session ends.
+    pub(crate) fn drop_connection(&self) {
+        if ::update(&self.is_connected, false).is_some() {
+            self.emit(FakeBluetoothEvent::Disconnected(self.get_id()));
+        }
+        self.update_services_resolved(false);
+        *::lock(&self.mtu) = ::DEFAULT_MTU;
+        *::lock(&self.prepared_writes) = None;
+        for service in ::lock(&self.gatt_services).iter() {
+            for characteristic in service.get_gatt_characteristics().unwrap_or_default() {
+                characteristic.end_notify();
+            }
+        }
+    }
+
+    fn update_services_resolved(&self, resolved: bool) {
+        if ::update(&self.is_services_resolved, resolved).is_some() {
+            self.property_changed("ServicesResolved", (!resolved).into(), resolved.into());
+        }
+    }
+
+    fn emit(&self, event: FakeBluetoothEvent) {
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.emit(event);
+        }
+    }
+
+    fn property_changed(&self, property: &str, old_value: FakePropertyValue, new_value: FakePropertyValue) {
+        self.emit(FakeBluetoothEvent::PropertyChanged {
+            object_path: self.get_id(),
+            property: String::from(property),
+            old_value,
+            new_value,
+        });
+    }
+
+    // A device nobody added to an adapter has no radio to worry about.
+    fn check_powered(&self) -> Result<(), FakeBluetoothError> {
+        match ::lock(&self.adapter).upgrade() {
+            Some(adapter) => adapter.check_powered(),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn is_gatt_visible(&self) -> bool {
+        *::lock(&self.gatt_policy) == FakeGattPolicy::Always || *::lock(&self.is_services_resolved)
+    }
+
+    pub(crate) fn check_gatt_access(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        self.check_powered()?;
+        if *::lock(&self.gatt_policy) == FakeGattPolicy::Always {
+            return Ok(());
+        }
+        if !*::lock(&self.is_connected) {
+            return Err(FakeBluetoothError::NotConnected);
+        }
+        if !*::lock(&self.is_services_resolved) {
+            return Err(FakeBluetoothError::InProgress(String::from("Service discovery in progress.")));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeBluetoothDeviceBuilder {
     address: String,
     appearance: u16,
     class: u32,
-    gatt_services: Vec<Arc<FakeBluetoothGATTService>>,
     is_paired: bool,
+    io_capability: FakeIoCapability,
+    passkey: u32,
+    pin_code: String,
     is_connectable: bool,
     is_connected: bool,
     is_trusted: bool,
@@ -26,64 +807,23 @@ pub struct FakeBluetoothDevice {
     rssi: i16,
     tx_power: i16,
     modalias: String,
+    gatt_policy: FakeGattPolicy,
+    max_mtu: u16,
+    discovery_schedule: FakeDiscoverySchedule,
+    disappears_on_stop: bool,
 }
 
-impl FakeBluetoothDevice {
-    pub fn new(object_path: String,
-               adapter: Arc<FakeBluetoothAdapter>,
-               address: String,
-               appearance: u16,
-               class: u32,
-               gatt_services: Vec<Arc<FakeBluetoothGATTService>>,
-               is_paired: bool,
-               is_connectable: bool,
-               is_connected: bool,
-               is_trusted: bool,
-               is_blocked: bool,
-               is_legacy_pairing: bool,
-               uuids: Vec<String>,
-               name: String,
-               icon: String,
-               alias: String,
-               product_version: u32,
-               rssi: i16,
-               tx_power: i16,
-               modalias: String)
-               -> FakeBluetoothDevice {
-        FakeBluetoothDevice{
-            object_path: object_path,
-            adapter: adapter,
-            address: address,
-            appearance: appearance,
-            class: class,
-            gatt_services: gatt_services,
-            is_paired: is_paired,
-            is_connectable: is_connectable,
-            is_connected: is_connected,
-            is_trusted: is_trusted,
-            is_blocked: is_blocked,
-            is_legacy_pairing: is_legacy_pairing,
-            uuids: uuids,
-            name: name,
-            icon: icon,
-            alias: alias,
-            product_version: product_version,
-            rssi: rssi,
-            tx_power: tx_power,
-            modalias: modalias,
-        }
-    }
-
-    pub fn new_empty() -> FakeBluetoothDevice {
-        FakeBluetoothDevice{
-            object_path: String::new(),
-            adapter: Arc::new(FakeBluetoothAdapter::new_empty()),
-            address: String::new(),
+impl Default for FakeBluetoothDeviceBuilder {
+    fn default() -> FakeBluetoothDeviceBuilder {
+        FakeBluetoothDeviceBuilder {
+            address: String::from("00:00:00:00:00:00"),
             appearance: 0,
             class: 0,
-            gatt_services: vec![],
             is_paired: false,
-            is_connectable: false,
+            io_capability: FakeIoCapability::default(),
+            passkey: 0,
+            pin_code: String::new(),
+            is_connectable: true,
             is_connected: false,
             is_trusted: false,
             is_blocked: false,
@@ -96,199 +836,413 @@ impl FakeBluetoothDevice {
             rssi: 0,
             tx_power: 0,
             modalias: String::new(),
+            gatt_policy: FakeGattPolicy::default(),
+            max_mtu: ::MAX_MTU,
+            discovery_schedule: FakeDiscoverySchedule::default(),
+            disappears_on_stop: false,
         }
     }
+}
+
+impl FakeBluetoothDeviceBuilder {
+    pub fn new() -> FakeBluetoothDeviceBuilder {
+        FakeBluetoothDeviceBuilder::default()
+    }
 
-    pub fn get_id(&self) -> String {
-        self.object_path.clone()
+    pub fn address(mut self, address: String) -> FakeBluetoothDeviceBuilder {
+        self.address = address;
+        self
     }
 
-    pub fn set_id(&mut self, object_path: String) {
-        self.object_path = object_path;
+    pub fn appearance(mut self, appearance: u16) -> FakeBluetoothDeviceBuilder {
+        self.appearance = appearance;
+        self
     }
 
-    pub fn get_adapter(&self) -> Result<Arc<FakeBluetoothAdapter>, Box<Error>> {
-        Ok(self.adapter.clone())
+    pub fn class(mut self, class: u32) -> FakeBluetoothDeviceBuilder {
+        self.class = class;
+        self
     }
 
-    pub fn set_adapter(&mut self, adapter: Arc<FakeBluetoothAdapter>) {
-        self.adapter = adapter;
+    pub fn paired(mut self, value: bool) -> FakeBluetoothDeviceBuilder {
+        self.is_paired = value;
+        self
     }
 
-    pub fn get_address(&self) -> Result<String, Box<Error>> {
-        Ok(self.address.clone())
+    pub fn connectable(mut self, value: bool) -> FakeBluetoothDeviceBuilder {
+        self.is_connectable = value;
+        self
     }
 
-    pub fn set_address(&mut self, address: String) {
-        self.address = address;
+    pub fn connected(mut self, value: bool) -> FakeBluetoothDeviceBuilder {
+        self.is_connected = value;
+        self
     }
 
-    pub fn get_name(&self) -> Result<String, Box<Error>> {
-        Ok(self.name.clone())
+    pub fn trusted(mut self, value: bool) -> FakeBluetoothDeviceBuilder {
+        self.is_trusted = value;
+        self
     }
 
-    pub fn set_name(&mut self, name: String) {
-        self.name = name;
+    pub fn blocked(mut self, value: bool) -> FakeBluetoothDeviceBuilder {
+        self.is_blocked = value;
+        self
     }
 
-    pub fn get_icon(&self) -> Result<String, Box<Error>> {
-        Ok(self.icon.clone())
+    pub fn legacy_pairing(mut self, value: bool) -> FakeBluetoothDeviceBuilder {
+        self.is_legacy_pairing = value;
+        self
     }
 
-    pub fn set_icon(&mut self, value: String) {
-        self.icon = value;
+    pub fn uuids(mut self, uuids: Vec<String>) -> FakeBluetoothDeviceBuilder {
+        self.uuids = uuids;
+        self
     }
 
-    pub fn get_class(&self) -> Result<u32, Box<Error>> {
-        Ok(self.class)
+    pub fn name(mut self, name: String) -> FakeBluetoothDeviceBuilder {
+        self.name = name;
+        self
     }
 
-    pub fn set_class(&mut self, value: u32) {
-        self.class = value;
+    pub fn icon(mut self, icon: String) -> FakeBluetoothDeviceBuilder {
+        self.icon = icon;
+        self
     }
 
-    pub fn get_appearance(&self) -> Result<u16, Box<Error>> {
-        Ok(self.appearance)
+    pub fn alias(mut self, alias: String) -> FakeBluetoothDeviceBuilder {
+        self.alias = alias;
+        self
     }
 
-    pub fn set_appearance(&mut self, appearance: u16) {
-        self.appearance = appearance;
+    pub fn product_version(mut self, product_version: u32) -> FakeBluetoothDeviceBuilder {
+        self.product_version = product_version;
+        self
     }
 
-    pub fn get_uuids(&self) -> Result<Vec<String>, Box<Error>> {
-        Ok(self.uuids.clone())
+    pub fn rssi(mut self, rssi: i16) -> FakeBluetoothDeviceBuilder {
+        self.rssi = rssi;
+        self
     }
 
-    pub fn set_uuids(&mut self, uuids: Vec<String>) {
-        self.uuids = uuids;
+    pub fn tx_power(mut self, tx_power: i16) -> FakeBluetoothDeviceBuilder {
+        self.tx_power = tx_power;
+        self
     }
 
-    pub fn is_paired(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_paired)
+    pub fn modalias(mut self, modalias: String) -> FakeBluetoothDeviceBuilder {
+        self.modalias = modalias;
+        self
     }
 
-    pub fn set_paired(&mut self, value: bool) {
-        self.is_paired = value;
+    pub fn io_capability(mut self, io_capability: FakeIoCapability) -> FakeBluetoothDeviceBuilder {
+        self.io_capability = io_capability;
+        self
     }
 
-    pub fn is_connected(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_connected)
+    pub fn passkey(mut self, passkey: u32) -> FakeBluetoothDeviceBuilder {
+        self.passkey = passkey;
+        self
     }
 
-    pub fn set_connected(&mut self, connected: bool) {
-        self.is_connected = connected;
+    pub fn pin_code(mut self, pin_code: String) -> FakeBluetoothDeviceBuilder {
+        self.pin_code = pin_code;
+        self
     }
 
-    pub fn is_trusted(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_trusted)
+    pub fn gatt_policy(mut self, gatt_policy: FakeGattPolicy) -> FakeBluetoothDeviceBuilder {
+        self.gatt_policy = gatt_policy;
+        self
     }
 
-    pub fn set_trusted(&mut self, value: bool) {
-        self.is_trusted = value;
+    pub fn max_mtu(mut self, max_mtu: u16) -> FakeBluetoothDeviceBuilder {
+        self.max_mtu = max_mtu;
+        self
     }
 
-    pub fn is_blocked(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_blocked)
+    pub fn discovery_schedule(mut self, discovery_schedule: FakeDiscoverySchedule) -> FakeBluetoothDeviceBuilder {
+        self.discovery_schedule = discovery_schedule;
+        self
     }
 
-    pub fn set_blocked(&mut self, blocked: bool) {
-        self.is_blocked = blocked;
+    pub fn disappears_on_stop(mut self, value: bool) -> FakeBluetoothDeviceBuilder {
+        self.disappears_on_stop = value;
+        self
     }
 
-    pub fn get_alias(&self) -> Result<String, Box<Error>> {
-        Ok(self.alias.clone())
+    pub fn build(self) -> Result<Arc<FakeBluetoothDevice>, FakeBluetoothBuildError> {
+        let address = ::parse_address(&self.address)?;
+        let uuids = ::parse_uuids(&self.uuids)?;
+        let modalias = ::parse_modalias(&self.modalias)?;
+        if self.max_mtu < ::DEFAULT_MTU {
+            return Err(FakeBluetoothBuildError::InvalidMtu(self.max_mtu));
+        }
+        Ok(Arc::new(FakeBluetoothDevice {
+            address: Mutex::new(address),
+            appearance: Mutex::new(self.appearance),
+            class: Mutex::new(self.class),
+            is_paired: Mutex::new(self.is_paired),
+            is_bonded: Mutex::new(self.is_paired),
+            io_capability: Mutex::new(self.io_capability),
+            passkey: Mutex::new(self.passkey),
+            pin_code: Mutex::new(self.pin_code),
+            is_connectable: Mutex::new(self.is_connectable),
+            is_connected: Mutex::new(self.is_connected && !self.is_blocked),
+            is_trusted: Mutex::new(self.is_trusted),
+            is_blocked: Mutex::new(self.is_blocked),
+            is_legacy_pairing: Mutex::new(self.is_legacy_pairing),
+            uuids: Mutex::new(uuids),
+            name: Mutex::new(self.name),
+            icon: Mutex::new(self.icon),
+            alias: Mutex::new(self.alias),
+            product_version: Mutex::new(self.product_version),
+            rssi: Mutex::new(self.rssi),
+            tx_power: Mutex::new(self.tx_power),
+            modalias: Mutex::new(modalias),
+            gatt_policy: Mutex::new(self.gatt_policy),
+            is_services_resolved: Mutex::new(self.is_connected && !self.is_blocked && self.gatt_policy != FakeGattPolicy::AfterServiceDiscovery),
+            max_mtu: Mutex::new(self.max_mtu),
+            discovery_schedule: Mutex::new(self.discovery_schedule),
+            disappears_on_stop: Mutex::new(self.disappears_on_stop),
+            is_discovered: Mutex::new(self.discovery_schedule == FakeDiscoverySchedule::Immediately),
+            ..FakeBluetoothDevice::new_empty()
+        }))
     }
+}
 
-    pub fn set_alias(&mut self, value: String) -> Result<(), Box<Error>> {
-        self.alias = value;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::{FakeBluetoothDevice, FakeBluetoothDeviceBuilder};
+    use fake_adapter::{FakeBluetoothAdapter, FakeBluetoothAdapterBuilder};
+    use fake_agent::{FakeAgent, FakeAgentError, FakeIoCapability};
+    use fake_error::{ATT_ERROR_INVALID_OFFSET, FakeBluetoothError};
+    use fake_service::FakeBluetoothGATTServiceBuilder;
+    use fake_test_support::{add_characteristic, connected_device};
+    use std::sync::{Arc, Weak};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Answers every request with the same passkey, also as the PIN code,
+    // or with the same error.
+    #[derive(Debug)]
+    struct ReplyingAgent(Result<u32, FakeAgentError>);
+
+    impl FakeAgent for ReplyingAgent {
+        fn request_pin_code(&self, _device: &str) -> Result<String, FakeAgentError> {
+            self.0.map(|passkey| passkey.to_string())
+        }
+
+        fn request_passkey(&self, _device: &str) -> Result<u32, FakeAgentError> {
+            self.0
+        }
+
+        fn request_confirmation(&self, _device: &str, _passkey: u32) -> Result<(), FakeAgentError> {
+            self.0.map(|_| ())
+        }
     }
 
-    pub fn is_legacy_pairing(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_legacy_pairing)
+    // Cancels the pairing while it is asked to confirm it.
+    #[derive(Debug)]
+    struct CancelingAgent {
+        device: Weak<FakeBluetoothDevice>,
+        canceled: AtomicBool,
     }
 
-    pub fn set_legacy_pairing(&mut self, value: bool) {
-        self.is_legacy_pairing = value;
+    impl FakeAgent for CancelingAgent {
+        fn request_confirmation(&self, _device: &str, _passkey: u32) -> Result<(), FakeAgentError> {
+            self.device.upgrade().unwrap().cancel_pairing().unwrap();
+            Ok(())
+        }
+
+        fn cancel(&self) {
+            self.canceled.store(true, Ordering::SeqCst);
+        }
     }
 
-    pub fn get_modalias(&self) ->  Result<(String, u32, u32, u32), Box<Error>> {
-        let ids: Vec<&str> = self.modalias.split(":").collect();
+    // A device expecting the passkey 123456, or the PIN code "123456".
+    fn pairing_device(io_capability: FakeIoCapability, legacy_pairing: bool)
+                      -> (Arc<FakeBluetoothAdapter>, Arc<FakeBluetoothDevice>) {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new()
+            .io_capability(io_capability)
+            .legacy_pairing(legacy_pairing)
+            .passkey(123456)
+            .pin_code(String::from("123456"))
+            .build()
+            .unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        (adapter, device)
+    }
 
-        let source = String::from(ids[0]);
-        let vendor = ids[1][1..5].from_hex().unwrap();
-        let product = ids[1][6..10].from_hex().unwrap();
-        let device = ids[1][11..15].from_hex().unwrap();
+    fn register_agent(adapter: &FakeBluetoothAdapter, agent: Arc<dyn FakeAgent>, capability: FakeIoCapability) {
+        adapter.get_agent_manager().register_agent(String::from("/agent"), agent, capability).unwrap();
+    }
 
-        Ok((source,
-        (vendor[0] as u32) * 16 * 16 + (vendor[1] as u32),
-        (product[0] as u32) * 16 * 16 + (product[1] as u32),
-        (device[0] as u32) * 16 * 16 + (device[1] as u32)))
+    fn assert_paired(device: &FakeBluetoothDevice, paired: bool) {
+        assert_eq!(device.is_paired(), Ok(paired));
+        assert_eq!(device.is_bonded(), Ok(paired));
     }
 
-    pub fn set_modalias(&mut self, value: String) {
-        self.modalias = value;
+    #[test]
+    fn setters_reject_what_the_builder_rejects() {
+        let device = FakeBluetoothDeviceBuilder::new().modalias(String::from("usb:v1D6B")).build().unwrap();
+        assert!(device.set_address(String::from("00:11:22:33:44")).is_err());
+        assert!(device.set_uuids(vec![String::from("bogus")]).is_err());
+        assert!(device.set_modalias(String::from("garbage")).is_err());
+        assert_eq!(device.get_vendor_id(), Ok(0x1d6b));
+        device.set_address(String::from("aa:bb:cc:dd:ee:ff")).unwrap();
+        assert_eq!(device.get_address(), Ok(String::from("AA:BB:CC:DD:EE:FF")));
     }
 
-    pub fn get_vendor_id_source(&self) -> Result<String, Box<Error>> {
-        let (vendor_id_source,_,_,_) = try!(self.get_modalias());
-        Ok(vendor_id_source)
+    #[test]
+    fn blocked_device_can_not_be_connected() {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new().blocked(true).build().unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        assert!(device.connect().is_err());
+        assert!(device.set_connected(true).is_err());
+        assert_eq!(device.is_connected(), Ok(false));
     }
 
-    pub fn get_vendor_id(&self) -> Result<u32, Box<Error>> {
-        let (_,vendor_id,_,_) = try!(self.get_modalias());
-        Ok(vendor_id)
+    #[test]
+    fn device_can_not_be_connected_behind_a_powered_off_adapter() {
+        let adapter = FakeBluetoothAdapterBuilder::new().powered(false).build().unwrap();
+        let device = FakeBluetoothDeviceBuilder::new().build().unwrap();
+        adapter.add_device(device.clone()).unwrap();
+        assert_eq!(device.connect(), Err(FakeBluetoothError::NotPowered));
+        assert_eq!(device.set_connected(true), Err(FakeBluetoothError::NotPowered));
+        assert_eq!(device.is_connected(), Ok(false));
     }
 
-    pub fn get_product_id(&self) -> Result<u32, Box<Error>> {
-        let (_,_,product_id,_) = try!(self.get_modalias());
-        Ok(product_id)
+    #[test]
+    fn reliable_write_shows_nothing_until_executed() {
+        let (_adapter, device) = connected_device();
+        let first = add_characteristic(&device, &["reliable-write"]);
+        let second = add_characteristic(&device, &["reliable-write"]);
+        device.begin_reliable_write().unwrap();
+        device.prepare_write(&first, 0, vec![4]).unwrap();
+        device.prepare_write(&second, 1, vec![5, 6]).unwrap();
+        device.prepare_write(&first, 1, vec![7]).unwrap();
+        assert_eq!(first.get_value(), Ok(vec![1, 2, 3]));
+        assert_eq!(second.get_value(), Ok(vec![1, 2, 3]));
+        device.execute_reliable_write().unwrap();
+        assert_eq!(first.get_value(), Ok(vec![4, 7]));
+        assert_eq!(second.get_value(), Ok(vec![1, 5, 6]));
     }
 
-    pub fn get_device_id(&self) -> Result<u32, Box<Error>> {
-        let (_,_,_,device_id) = try!(self.get_modalias());
-        Ok(device_id)
+    #[test]
+    fn reliable_write_with_an_invalid_offset_changes_nothing() {
+        let (_adapter, device) = connected_device();
+        let first = add_characteristic(&device, &["reliable-write"]);
+        let second = add_characteristic(&device, &["reliable-write"]);
+        device.begin_reliable_write().unwrap();
+        device.prepare_write(&first, 0, vec![4]).unwrap();
+        device.prepare_write(&second, 4, vec![5]).unwrap();
+        assert_eq!(device.execute_reliable_write(), Err(FakeBluetoothError::AttError(ATT_ERROR_INVALID_OFFSET)));
+        assert_eq!(first.get_value(), Ok(vec![1, 2, 3]));
+        assert_eq!(second.get_value(), Ok(vec![1, 2, 3]));
+        assert!(device.execute_reliable_write().is_err());
     }
 
-    pub fn get_rssi(&self) -> Result<i16, Box<Error>> {
-        Ok(self.rssi)
+    #[test]
+    fn aborted_reliable_write_discards_the_queue() {
+        let (_adapter, device) = connected_device();
+        let characteristic = add_characteristic(&device, &["reliable-write"]);
+        device.begin_reliable_write().unwrap();
+        device.prepare_write(&characteristic, 0, vec![4]).unwrap();
+        device.abort_reliable_write().unwrap();
+        assert!(device.execute_reliable_write().is_err());
+        device.begin_reliable_write().unwrap();
+        device.execute_reliable_write().unwrap();
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
     }
 
-    pub fn set_rssi(&mut self, rssi: i16) {
-        self.rssi = rssi;
+    #[test]
+    fn disconnect_drops_the_reliable_write() {
+        let (_adapter, device) = connected_device();
+        let characteristic = add_characteristic(&device, &["reliable-write"]);
+        device.begin_reliable_write().unwrap();
+        device.prepare_write(&characteristic, 0, vec![4]).unwrap();
+        device.disconnect().unwrap();
+        device.connect().unwrap();
+        assert!(device.execute_reliable_write().is_err());
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
     }
 
-    pub fn get_tx_power(&self) -> Result<i16, Box<Error>> {
-        Ok(self.tx_power)
+    #[test]
+    fn prepare_write_rejects_another_devices_characteristic() {
+        let (adapter, device) = connected_device();
+        let other = FakeBluetoothDeviceBuilder::new().address(String::from("66:77:88:99:AA:BB")).build().unwrap();
+        adapter.add_device(other.clone()).unwrap();
+        other.add_service(FakeBluetoothGATTServiceBuilder::new().uuid(String::from("180f")).build().unwrap()).unwrap();
+        other.connect().unwrap();
+        let characteristic = add_characteristic(&other, &["reliable-write"]);
+        device.begin_reliable_write().unwrap();
+        assert!(matches!(device.prepare_write(&characteristic, 0, vec![4]), Err(FakeBluetoothError::InvalidArgument(_))));
+        device.execute_reliable_write().unwrap();
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
     }
 
-    pub fn set_tx_power(&mut self, tx_power: i16) {
-        self.tx_power = tx_power;
+    #[test]
+    fn pair_succeeds_when_the_agent_accepts() {
+        let (adapter, device) = pairing_device(FakeIoCapability::DisplayYesNo, false);
+        register_agent(&adapter, Arc::new(ReplyingAgent(Ok(123456))), FakeIoCapability::DisplayYesNo);
+        device.pair().unwrap();
+        assert_eq!(device.is_connected(), Ok(true));
+        assert_paired(&device, true);
     }
 
-    pub fn get_gatt_services(&self) -> Result<Vec<Arc<FakeBluetoothGATTService>>, Box<Error>> {
-        Ok(self.gatt_services.clone())
+    #[test]
+    fn pair_fails_when_the_agent_rejects() {
+        let (adapter, device) = pairing_device(FakeIoCapability::DisplayYesNo, false);
+        register_agent(&adapter, Arc::new(ReplyingAgent(Err(FakeAgentError::Rejected))), FakeIoCapability::DisplayYesNo);
+        assert_eq!(device.pair(), Err(FakeBluetoothError::AuthenticationRejected));
+        assert_paired(&device, false);
     }
 
-    pub fn set_gatt_service(&mut self, services: Vec<Arc<FakeBluetoothGATTService>>) {
-        self.gatt_services = services;
+    #[test]
+    fn pair_fails_on_a_wrong_passkey() {
+        let (adapter, device) = pairing_device(FakeIoCapability::DisplayOnly, false);
+        register_agent(&adapter, Arc::new(ReplyingAgent(Ok(654321))), FakeIoCapability::KeyboardOnly);
+        assert_eq!(device.pair(), Err(FakeBluetoothError::AuthenticationFailed));
+        assert_paired(&device, false);
     }
 
-    pub fn connect(&mut self) -> Result<(), Box<Error>> {
-        if self.is_connectable && !self.is_connected {
-            self.is_connected = true;
-            return Ok(());
-        } else {
-            return Err(Box::from("Could not connect to the device."));
-        }
+    #[test]
+    fn pair_fails_on_a_wrong_pin_code() {
+        let (adapter, device) = pairing_device(FakeIoCapability::NoInputNoOutput, true);
+        register_agent(&adapter, Arc::new(ReplyingAgent(Ok(654321))), FakeIoCapability::KeyboardDisplay);
+        assert_eq!(device.pair(), Err(FakeBluetoothError::AuthenticationFailed));
+        assert_paired(&device, false);
     }
 
-    pub fn disconnect(&mut self) -> Result<(), Box<Error>>{
-        if self.is_connected {
-            self.is_connected = false;
-            return Ok(());
-        } else {
-            return Err(Box::from("The device is not connected."));
-        }
+    #[test]
+    fn pair_times_out_with_the_agent() {
+        let (adapter, device) = pairing_device(FakeIoCapability::DisplayYesNo, false);
+        register_agent(&adapter, Arc::new(ReplyingAgent(Err(FakeAgentError::TimedOut))), FakeIoCapability::DisplayYesNo);
+        assert_eq!(device.pair(), Err(FakeBluetoothError::AuthenticationTimeout));
+        assert_paired(&device, false);
+    }
+
+    #[test]
+    fn canceled_pairing_fails_and_tells_the_agent() {
+        let (adapter, device) = pairing_device(FakeIoCapability::DisplayYesNo, false);
+        let agent = Arc::new(CancelingAgent {
+            device: Arc::downgrade(&device),
+            canceled: AtomicBool::new(false),
+        });
+        register_agent(&adapter, agent.clone(), FakeIoCapability::DisplayYesNo);
+        assert_eq!(device.pair(), Err(FakeBluetoothError::AuthenticationCanceled));
+        assert!(agent.canceled.load(Ordering::SeqCst));
+        assert_paired(&device, false);
+        assert!(device.cancel_pairing().is_err());
+    }
+
+    #[test]
+    fn legacy_pairing_needs_an_agent() {
+        let (_adapter, device) = pairing_device(FakeIoCapability::DisplayYesNo, true);
+        assert_eq!(device.pair(), Err(FakeBluetoothError::AuthenticationFailed));
+        assert_paired(&device, false);
+        device.set_legacy_pairing(false).unwrap();
+        device.pair().unwrap();
+        assert_paired(&device, true);
     }
 }
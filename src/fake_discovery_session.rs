@@ -1,20 +1,115 @@
 use fake_adapter::FakeBluetoothAdapter;
-use std::error::Error;
-use std::sync::Arc; 
+use fake_device::FakeBluetoothDevice;
+use fake_error::FakeBluetoothError;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_SESSION_ID: AtomicUsize = AtomicUsize::new(0);
+
+const TRANSPORTS: &[&str] = &["auto", "bredr", "le"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FakeDiscoveryFilter {
+    uuids: Vec<String>,
+    rssi: Option<i16>,
+    pathloss: Option<u16>,
+    transport: String,
+}
+
+impl FakeDiscoveryFilter {
+    pub fn get_uuids(&self) -> Vec<String> {
+        self.uuids.clone()
+    }
+
+    pub fn get_rssi(&self) -> Option<i16> {
+        self.rssi
+    }
+
+    pub fn get_pathloss(&self) -> Option<u16> {
+        self.pathloss
+    }
+
+    pub fn get_transport(&self) -> String {
+        self.transport.clone()
+    }
+
+    // Transport only selects the scan type, so it never rules a device out.
+    pub(crate) fn matches(&self, device: &FakeBluetoothDevice) -> bool {
+        let uuids = device.get_uuids().unwrap_or_default();
+        let rssi = device.get_rssi().unwrap_or_default();
+        let tx_power = device.get_tx_power().unwrap_or_default();
+        (self.uuids.is_empty() || self.uuids.iter().any(|uuid| uuids.contains(uuid))) &&
+        self.rssi.is_none_or(|threshold| rssi >= threshold) &&
+        self.pathloss.is_none_or(|threshold| i32::from(tx_power) - i32::from(rssi) <= i32::from(threshold))
+    }
+
+    // Mirrors BlueZ's merge_discovery_filters: any unfiltered session or empty
+    // UUID list widens the result to everything, otherwise the UUIDs are joined,
+    // the lowest RSSI and highest pathloss win and an RSSI threshold takes
+    // precedence over a pathloss one.
+    pub(crate) fn merge<'a, I>(filters: I) -> Option<FakeDiscoveryFilter>
+        where I: IntoIterator<Item = &'a Option<FakeDiscoveryFilter>>
+    {
+        let mut merged: Option<FakeDiscoveryFilter> = None;
+        let mut any_uuid = false;
+        for filter in filters {
+            let filter = match *filter {
+                Some(ref filter) => filter,
+                None => return None,
+            };
+            any_uuid |= filter.uuids.is_empty();
+            merged = Some(match merged {
+                None => filter.clone(),
+                Some(mut merged) => {
+                    for uuid in &filter.uuids {
+                        if !merged.uuids.contains(uuid) {
+                            merged.uuids.push(uuid.clone());
+                        }
+                    }
+                    merged.rssi = match (merged.rssi, filter.rssi) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+                    merged.pathloss = match (merged.pathloss, filter.pathloss) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        (a, b) => a.or(b),
+                    };
+                    if merged.transport != filter.transport {
+                        merged.transport = String::from("auto");
+                    }
+                    merged
+                },
+            });
+        }
+        merged.map(|mut merged| {
+            if any_uuid {
+                merged.uuids.clear();
+            }
+            if merged.rssi.is_some() {
+                merged.pathloss = None;
+            }
+            merged
+        })
+    }
+}
 
 #[derive(Debug)]
 pub struct FakeBluetoothDiscoverySession {
+    id: usize,
     adapter: Arc<FakeBluetoothAdapter>,
+    filter: Mutex<Option<FakeDiscoveryFilter>>,
 }
 
 impl FakeBluetoothDiscoverySession {
-    pub fn create_session(adapter: Arc<FakeBluetoothAdapter>) -> Result<FakeBluetoothDiscoverySession, Box<Error>> {
+    pub fn create_session(adapter: Arc<FakeBluetoothAdapter>) -> Result<FakeBluetoothDiscoverySession, FakeBluetoothError> {
         Ok(FakeBluetoothDiscoverySession::new(adapter))
     }
 
     fn new(adapter: Arc<FakeBluetoothAdapter>) -> FakeBluetoothDiscoverySession {
         FakeBluetoothDiscoverySession {
-            adapter: adapter,
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::SeqCst),
+            adapter,
+            filter: Mutex::new(None),
         }
     }
 
@@ -22,11 +117,173 @@ impl FakeBluetoothDiscoverySession {
         self.adapter.clone()
     }
 
-    pub fn start_discovery(&self) -> Result<(), Box<Error>> {
-        Ok(())
+    // The adapter keeps track, as powering it off ends every session.
+    pub fn is_active(&self) -> bool {
+        self.adapter.is_session_active(self.id)
     }
 
-    pub fn stop_discovery(&self) -> Result<(), Box<Error>> {
+    pub fn get_discovery_filter(&self) -> Option<FakeDiscoveryFilter> {
+        ::lock(&self.filter).clone()
+    }
+
+    // An empty filter (no UUIDs, thresholds or transport) clears it again.
+    pub fn set_discovery_filter(&self,
+                                uuids: Vec<String>,
+                                rssi: Option<i16>,
+                                pathloss: Option<u16>,
+                                transport: String)
+                                -> Result<(), FakeBluetoothError> {
+        if rssi.is_some() && pathloss.is_some() {
+            return Err(FakeBluetoothError::InvalidArgument(String::from("RSSI and pathloss can not be set together.")));
+        }
+        let transport = if transport.is_empty() { String::from("auto") } else { transport };
+        if !TRANSPORTS.contains(&transport.as_str()) {
+            return Err(FakeBluetoothError::InvalidArgument(format!("Invalid transport: {:?}.", transport)));
+        }
+        let uuids = ::parse_uuids(&uuids)?;
+
+        let filter = if uuids.is_empty() && rssi.is_none() && pathloss.is_none() && transport == "auto" {
+            None
+        } else {
+            Some(FakeDiscoveryFilter {
+                uuids,
+                rssi,
+                pathloss,
+                transport,
+            })
+        };
+        *::lock(&self.filter) = filter.clone();
+        if self.is_active() {
+            self.adapter.update_session_filter(self.id, filter);
+        }
         Ok(())
     }
+
+    pub fn start_discovery(&self) -> Result<(), FakeBluetoothError> {
+        if self.is_active() {
+            return Err(FakeBluetoothError::InProgress(String::from("Discovery already started.")));
+        }
+        self.adapter.start_session_discovery(self.id, self.get_discovery_filter())
+    }
+
+    pub fn stop_discovery(&self) -> Result<(), FakeBluetoothError> {
+        if !self.is_active() {
+            return Err(FakeBluetoothError::Failed(String::from("No discovery started.")));
+        }
+        self.adapter.stop_session_discovery(self.id)
+    }
+}
+
+// BlueZ drops a client's discovery request when the client goes away.
+impl Drop for FakeBluetoothDiscoverySession {
+    fn drop(&mut self) {
+        self.adapter.release_session_discovery(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FakeDiscoveryFilter;
+    use fake_adapter::FakeBluetoothAdapterBuilder;
+    use fake_device::{FakeBluetoothDeviceBuilder, FakeDiscoverySchedule};
+
+    fn filter(uuids: &[&str], rssi: Option<i16>, pathloss: Option<u16>, transport: &str) -> Option<FakeDiscoveryFilter> {
+        Some(FakeDiscoveryFilter {
+            uuids: ::parse_uuids(&uuids.iter().map(|uuid| String::from(*uuid)).collect::<Vec<_>>()).unwrap(),
+            rssi,
+            pathloss,
+            transport: String::from(transport),
+        })
+    }
+
+    #[test]
+    fn merge_of_nothing_is_no_filter() {
+        assert_eq!(FakeDiscoveryFilter::merge(&[]), None);
+    }
+
+    #[test]
+    fn unfiltered_session_widens_to_everything() {
+        assert_eq!(FakeDiscoveryFilter::merge(&[filter(&["180d"], Some(-60), None, "le"), None]), None);
+    }
+
+    #[test]
+    fn uuids_are_joined_without_duplicates() {
+        let merged = FakeDiscoveryFilter::merge(&[filter(&["180d", "180f"], None, None, "le"),
+                                                  filter(&["180F", "1812"], None, None, "le")]).unwrap();
+        assert_eq!(merged.get_uuids(), ::parse_uuids(&[String::from("180d"), String::from("180f"), String::from("1812")]).unwrap());
+        assert_eq!(merged.get_transport(), "le");
+    }
+
+    #[test]
+    fn empty_uuid_list_widens_to_any_uuid() {
+        let merged = FakeDiscoveryFilter::merge(&[filter(&["180d"], None, None, "le"), filter(&[], Some(-70), None, "le")]).unwrap();
+        assert!(merged.get_uuids().is_empty());
+    }
+
+    #[test]
+    fn lowest_rssi_and_highest_pathloss_win() {
+        let merged = FakeDiscoveryFilter::merge(&[filter(&[], Some(-60), None, "le"), filter(&[], Some(-80), None, "le")]).unwrap();
+        assert_eq!(merged.get_rssi(), Some(-80));
+        let merged = FakeDiscoveryFilter::merge(&[filter(&[], None, Some(20), "le"), filter(&[], None, Some(40), "le")]).unwrap();
+        assert_eq!(merged.get_pathloss(), Some(40));
+    }
+
+    #[test]
+    fn rssi_takes_precedence_over_pathloss() {
+        let merged = FakeDiscoveryFilter::merge(&[filter(&[], Some(-60), None, "le"), filter(&[], None, Some(40), "le")]).unwrap();
+        assert_eq!(merged.get_rssi(), Some(-60));
+        assert_eq!(merged.get_pathloss(), None);
+    }
+
+    #[test]
+    fn differing_transports_fall_back_to_auto() {
+        let merged = FakeDiscoveryFilter::merge(&[filter(&[], None, None, "le"), filter(&[], None, None, "bredr")]).unwrap();
+        assert_eq!(merged.get_transport(), "auto");
+    }
+
+    #[test]
+    fn matches_short_uuids_in_any_case() {
+        let device = FakeBluetoothDeviceBuilder::new().build().unwrap();
+        device.set_uuids(vec![String::from("180D")]).unwrap();
+        assert!(filter(&["180d"], None, None, "auto").unwrap().matches(&device));
+        assert!(!filter(&["180f"], None, None, "auto").unwrap().matches(&device));
+    }
+
+    #[test]
+    fn matches_rssi_and_pathloss_thresholds() {
+        let device = FakeBluetoothDeviceBuilder::new().build().unwrap();
+        device.set_rssi(-70).unwrap();
+        device.set_tx_power(-10).unwrap();
+        assert!(filter(&[], Some(-70), None, "auto").unwrap().matches(&device));
+        assert!(!filter(&[], Some(-69), None, "auto").unwrap().matches(&device));
+        assert!(filter(&[], None, Some(60), "auto").unwrap().matches(&device));
+        assert!(!filter(&[], None, Some(59), "auto").unwrap().matches(&device));
+    }
+
+    #[test]
+    fn filtered_discovery_lists_only_matching_devices() {
+        let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+        let heart_rate = FakeBluetoothDeviceBuilder::new()
+            .address(String::from("00:00:00:00:00:01"))
+            .uuids(vec![String::from("180d")])
+            .discovery_schedule(FakeDiscoverySchedule::WhileDiscovering)
+            .build()
+            .unwrap();
+        let battery = FakeBluetoothDeviceBuilder::new()
+            .address(String::from("00:00:00:00:00:02"))
+            .uuids(vec![String::from("180f")])
+            .discovery_schedule(FakeDiscoverySchedule::WhileDiscovering)
+            .build()
+            .unwrap();
+        adapter.set_devices(vec![heart_rate.clone(), battery.clone()]).unwrap();
+
+        let session = adapter.create_discovery_session().unwrap();
+        session.set_discovery_filter(vec![String::from("180D")], None, None, String::from("le")).unwrap();
+        session.start_discovery().unwrap();
+        let ids: Vec<String> = adapter.get_device_list().unwrap().iter().map(|device| device.get_id()).collect();
+        assert_eq!(ids, vec![heart_rate.get_id()]);
+
+        session.set_discovery_filter(vec![], None, None, String::new()).unwrap();
+        assert_eq!(adapter.get_device_list().unwrap().len(), 2);
+    }
 }
@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt;
+
+pub const ATT_ERROR_INVALID_OFFSET: u8 = 0x07;
+pub const ATT_ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FakeBluetoothOperation {
+    Read,
+    Write,
+    Notify,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FakeBluetoothError {
+    NotFound(String),
+    AlreadyExists(String),
+    NotConnected,
+    AlreadyConnected,
+    NotConnectable,
+    NotPermitted(FakeBluetoothOperation),
+    NotSupported(String),
+    InProgress(String),
+    Failed(String),
+    InvalidArgument(String),
+    NotPowered,
+    AuthenticationFailed,
+    AuthenticationRejected,
+    AuthenticationCanceled,
+    AuthenticationTimeout,
+    NotAuthorized,
+    // An ATT error code answered by the peripheral.
+    AttError(u8),
+}
+
+impl FakeBluetoothError {
+    // The org.bluez.Error.* name BlueZ would reply with for this error.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FakeBluetoothError::NotFound(_) => "org.bluez.Error.DoesNotExist",
+            FakeBluetoothError::AlreadyExists(_) => "org.bluez.Error.AlreadyExists",
+            FakeBluetoothError::NotConnected => "org.bluez.Error.NotConnected",
+            FakeBluetoothError::AlreadyConnected => "org.bluez.Error.AlreadyConnected",
+            FakeBluetoothError::NotConnectable => "org.bluez.Error.Failed",
+            FakeBluetoothError::NotPermitted(_) => "org.bluez.Error.NotPermitted",
+            FakeBluetoothError::NotSupported(_) => "org.bluez.Error.NotSupported",
+            FakeBluetoothError::InProgress(_) => "org.bluez.Error.InProgress",
+            FakeBluetoothError::Failed(_) => "org.bluez.Error.Failed",
+            FakeBluetoothError::InvalidArgument(_) => "org.bluez.Error.InvalidArguments",
+            FakeBluetoothError::NotPowered => "org.bluez.Error.NotReady",
+            FakeBluetoothError::AuthenticationFailed => "org.bluez.Error.AuthenticationFailed",
+            FakeBluetoothError::AuthenticationRejected => "org.bluez.Error.AuthenticationRejected",
+            FakeBluetoothError::AuthenticationCanceled => "org.bluez.Error.AuthenticationCanceled",
+            FakeBluetoothError::AuthenticationTimeout => "org.bluez.Error.AuthenticationTimeout",
+            FakeBluetoothError::NotAuthorized => "org.bluez.Error.NotAuthorized",
+            FakeBluetoothError::AttError(code) => match code {
+                0x02 | 0x03 => "org.bluez.Error.NotPermitted",
+                0x05 | 0x08 | 0x0f => "org.bluez.Error.NotAuthorized",
+                0x06 => "org.bluez.Error.NotSupported",
+                ATT_ERROR_INVALID_OFFSET => "org.bluez.Error.InvalidOffset",
+                ATT_ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH => "org.bluez.Error.InvalidValueLength",
+                _ => "org.bluez.Error.Failed",
+            },
+        }
+    }
+}
+
+impl fmt::Display for FakeBluetoothError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FakeBluetoothError::NotFound(ref message) |
+            FakeBluetoothError::AlreadyExists(ref message) |
+            FakeBluetoothError::NotSupported(ref message) |
+            FakeBluetoothError::InProgress(ref message) |
+            FakeBluetoothError::Failed(ref message) |
+            FakeBluetoothError::InvalidArgument(ref message) => write!(f, "{}", message),
+            FakeBluetoothError::NotConnected => write!(f, "The device is not connected."),
+            FakeBluetoothError::AlreadyConnected => write!(f, "The device is already connected."),
+            FakeBluetoothError::NotConnectable => write!(f, "Could not connect to the device."),
+            FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Read) => write!(f, "Read not permitted."),
+            FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Write) => write!(f, "Write not permitted."),
+            FakeBluetoothError::NotPermitted(FakeBluetoothOperation::Notify) => write!(f, "Notify not permitted."),
+            FakeBluetoothError::NotPowered => write!(f, "The adapter is not powered."),
+            FakeBluetoothError::AuthenticationFailed => write!(f, "Authentication failed."),
+            FakeBluetoothError::AuthenticationRejected => write!(f, "Authentication rejected."),
+            FakeBluetoothError::AuthenticationCanceled => write!(f, "Authentication canceled."),
+            FakeBluetoothError::AuthenticationTimeout => write!(f, "Authentication timed out."),
+            FakeBluetoothError::NotAuthorized => write!(f, "Not authorized."),
+            FakeBluetoothError::AttError(code) => write!(f, "Operation failed with ATT error: 0x{:02x}.", code),
+        }
+    }
+}
+
+impl Error for FakeBluetoothError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FakeBluetoothBuildError {
+    InvalidAddress(String),
+    InvalidUuid(String),
+    InvalidFlag(String),
+    InvalidModalias(String),
+    InvalidMtu(u16),
+}
+
+impl fmt::Display for FakeBluetoothBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FakeBluetoothBuildError::InvalidAddress(ref address) => write!(f, "Invalid address: {:?}.", address),
+            FakeBluetoothBuildError::InvalidUuid(ref uuid) => write!(f, "Invalid UUID: {:?}.", uuid),
+            FakeBluetoothBuildError::InvalidFlag(ref flag) => write!(f, "Invalid flag: {:?}.", flag),
+            FakeBluetoothBuildError::InvalidModalias(ref modalias) => write!(f, "Invalid modalias: {:?}.", modalias),
+            FakeBluetoothBuildError::InvalidMtu(mtu) => write!(f, "Invalid MTU: {}.", mtu),
+        }
+    }
+}
+
+impl Error for FakeBluetoothBuildError {}
+
+impl From<FakeBluetoothBuildError> for FakeBluetoothError {
+    fn from(error: FakeBluetoothBuildError) -> FakeBluetoothError {
+        FakeBluetoothError::InvalidArgument(error.to_string())
+    }
+}
@@ -0,0 +1,76 @@
+// Events carry the object path of the object they concern. Property names
+// are the ones BlueZ uses on D-Bus, e.g. "Powered" or "RSSI".
+#[derive(Clone, Debug, PartialEq)]
+pub enum FakeBluetoothEvent {
+    // The device became visible on the adapter (InterfacesAdded).
+    DeviceAdded(String),
+    // The device is no longer visible on the adapter (InterfacesRemoved).
+    DeviceRemoved(String),
+    PropertyChanged {
+        object_path: String,
+        property: String,
+        old_value: FakePropertyValue,
+        new_value: FakePropertyValue,
+    },
+    // Sent for every characteristic value update, even when the value stays
+    // the same, like a notification would be.
+    ValueChanged {
+        object_path: String,
+        value: Vec<u8>,
+    },
+    Connected(String),
+    Disconnected(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FakePropertyValue {
+    Bool(bool),
+    I16(i16),
+    U16(u16),
+    U32(u32),
+    String(String),
+    Strings(Vec<String>),
+    Bytes(Vec<u8>),
+}
+
+impl From<bool> for FakePropertyValue {
+    fn from(value: bool) -> FakePropertyValue {
+        FakePropertyValue::Bool(value)
+    }
+}
+
+impl From<i16> for FakePropertyValue {
+    fn from(value: i16) -> FakePropertyValue {
+        FakePropertyValue::I16(value)
+    }
+}
+
+impl From<u16> for FakePropertyValue {
+    fn from(value: u16) -> FakePropertyValue {
+        FakePropertyValue::U16(value)
+    }
+}
+
+impl From<u32> for FakePropertyValue {
+    fn from(value: u32) -> FakePropertyValue {
+        FakePropertyValue::U32(value)
+    }
+}
+
+impl From<String> for FakePropertyValue {
+    fn from(value: String) -> FakePropertyValue {
+        FakePropertyValue::String(value)
+    }
+}
+
+impl From<Vec<String>> for FakePropertyValue {
+    fn from(value: Vec<String>) -> FakePropertyValue {
+        FakePropertyValue::Strings(value)
+    }
+}
+
+impl From<Vec<u8>> for FakePropertyValue {
+    fn from(value: Vec<u8>) -> FakePropertyValue {
+        FakePropertyValue::Bytes(value)
+    }
+}
@@ -0,0 +1,256 @@
+use fake_characteristic::FakeBluetoothGATTCharacteristic;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+// Where a characteristic's generated values come from. The generator only
+// advances while the characteristic is notifying.
+pub enum FakeValueGenerator {
+    // Cycles through the values for as long as it runs.
+    Sequence(Vec<Vec<u8>>),
+    // A single byte counting up from `start` by `step`, wrapping around.
+    Counter { start: u8, step: u8 },
+    // Called with the number of values generated so far.
+    Closure(Box<dyn FnMut(u64) -> Vec<u8> + Send>),
+    // Plays the recorded values once, then stops.
+    Replay(Vec<Vec<u8>>),
+}
+
+impl FakeValueGenerator {
+    fn next_value(&mut self, count: u64) -> Option<Vec<u8>> {
+        match *self {
+            FakeValueGenerator::Sequence(ref values) => {
+                if values.is_empty() {
+                    return None;
+                }
+                Some(values[(count % values.len() as u64) as usize].clone())
+            },
+            FakeValueGenerator::Counter { start, step } => Some(vec![start.wrapping_add(step.wrapping_mul(count as u8))]),
+            FakeValueGenerator::Closure(ref mut generate) => Some(generate(count)),
+            FakeValueGenerator::Replay(ref values) => values.get(count as usize).cloned(),
+        }
+    }
+}
+
+impl fmt::Debug for FakeValueGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FakeValueGenerator::Sequence(ref values) => f.debug_tuple("Sequence").field(values).finish(),
+            FakeValueGenerator::Counter { start, step } => {
+                f.debug_struct("Counter").field("start", &start).field("step", &step).finish()
+            },
+            FakeValueGenerator::Closure(_) => f.write_str("Closure(..)"),
+            FakeValueGenerator::Replay(ref values) => f.debug_tuple("Replay").field(values).finish(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum FakeClock {
+    // Values are generated from a background thread as real time passes.
+    Real,
+    // Values are only generated by FakeVirtualClock::advance(), on the
+    // calling thread, which keeps tests deterministic.
+    Virtual(FakeVirtualClock),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FakeVirtualClock {
+    now: Arc<Mutex<Duration>>,
+    tasks: Arc<Mutex<Vec<Weak<FakeGeneratorTask>>>>,
+}
+
+impl FakeVirtualClock {
+    pub fn new() -> FakeVirtualClock {
+        FakeVirtualClock::default()
+    }
+
+    pub fn now(&self) -> Duration {
+        *::lock(&self.now)
+    }
+
+    // Fires every generator that falls due on the way, in order of time.
+    pub fn advance(&self, duration: Duration) {
+        let target = self.now() + duration;
+        loop {
+            let tasks: Vec<Arc<FakeGeneratorTask>> = {
+                let mut tasks = ::lock(&self.tasks);
+                tasks.retain(|task| task.upgrade().is_some_and(|task| !task.is_cancelled()));
+                tasks.iter().filter_map(Weak::upgrade).collect()
+            };
+            let next = tasks.iter()
+                            .map(|task| (task.next_due(), task))
+                            .filter(|&(due, _)| due <= target)
+                            .min_by_key(|&(due, _)| due);
+            match next {
+                Some((due, task)) => {
+                    *::lock(&self.now) = due;
+                    task.fire();
+                },
+                None => break,
+            }
+        }
+        *::lock(&self.now) = target;
+    }
+
+    fn schedule(&self, task: &Arc<FakeGeneratorTask>) {
+        *::lock(&task.next_due) = self.now() + task.interval;
+        ::lock(&self.tasks).push(Arc::downgrade(task));
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FakeGeneratorTask {
+    characteristic: Weak<FakeBluetoothGATTCharacteristic>,
+    generator: Mutex<FakeValueGenerator>,
+    interval: Duration,
+    count: Mutex<u64>,
+    next_due: Mutex<Duration>,
+    is_cancelled: Mutex<bool>,
+}
+
+impl FakeGeneratorTask {
+    pub(crate) fn start(characteristic: &Arc<FakeBluetoothGATTCharacteristic>,
+                        generator: FakeValueGenerator,
+                        interval: Duration,
+                        clock: FakeClock)
+                        -> Arc<FakeGeneratorTask> {
+        let task = Arc::new(FakeGeneratorTask {
+            characteristic: Arc::downgrade(characteristic),
+            generator: Mutex::new(generator),
+            interval,
+            count: Mutex::new(0),
+            next_due: Mutex::new(Duration::from_secs(0)),
+            is_cancelled: Mutex::new(false),
+        });
+        match clock {
+            FakeClock::Real => {
+                let task = task.clone();
+                thread::spawn(move || {
+                    while !task.is_cancelled() {
+                        thread::sleep(task.interval);
+                        task.fire();
+                    }
+                });
+            },
+            FakeClock::Virtual(clock) => clock.schedule(&task),
+        }
+        task
+    }
+
+    pub(crate) fn cancel(&self) {
+        *::lock(&self.is_cancelled) = true;
+    }
+
+    fn is_cancelled(&self) -> bool {
+        *::lock(&self.is_cancelled)
+    }
+
+    fn next_due(&self) -> Duration {
+        *::lock(&self.next_due)
+    }
+
+    fn fire(&self) {
+        *::lock(&self.next_due) += self.interval;
+        if self.is_cancelled() {
+            return;
+        }
+        let characteristic = match self.characteristic.upgrade() {
+            Some(characteristic) => characteristic,
+            None => return self.cancel(),
+        };
+        if !characteristic.is_notifying().unwrap_or(false) {
+            return;
+        }
+        let mut count = ::lock(&self.count);
+        match ::lock(&self.generator).next_value(*count) {
+            Some(value) => {
+                *count += 1;
+                if characteristic.notify_value(value).is_err() {
+                    self.cancel();
+                }
+            },
+            None => self.cancel(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FakeClock, FakeValueGenerator, FakeVirtualClock};
+    use fake_test_support::{connected_characteristic, pending};
+    use std::time::Duration;
+
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    // The values `generator` notifies over `ticks` intervals.
+    fn generate(generator: FakeValueGenerator, ticks: u32) -> Vec<Vec<u8>> {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let clock = FakeVirtualClock::new();
+        characteristic.set_generator(generator, INTERVAL, FakeClock::Virtual(clock.clone())).unwrap();
+        let stream = characteristic.start_notify().unwrap();
+        clock.advance(INTERVAL * ticks);
+        pending(&stream)
+    }
+
+    #[test]
+    fn sequence_cycles() {
+        assert_eq!(generate(FakeValueGenerator::Sequence(vec![vec![1], vec![2]]), 3), vec![vec![1], vec![2], vec![1]]);
+    }
+
+    #[test]
+    fn counter_counts_and_wraps() {
+        assert_eq!(generate(FakeValueGenerator::Counter { start: 250, step: 3 }, 3), vec![vec![250], vec![253], vec![0]]);
+    }
+
+    #[test]
+    fn closure_gets_the_count() {
+        let generator = FakeValueGenerator::Closure(Box::new(|count| vec![count as u8 * 2]));
+        assert_eq!(generate(generator, 3), vec![vec![0], vec![2], vec![4]]);
+    }
+
+    #[test]
+    fn replay_stops_after_its_last_value() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let clock = FakeVirtualClock::new();
+        let generator = FakeValueGenerator::Replay(vec![vec![1], vec![2]]);
+        characteristic.set_generator(generator, INTERVAL, FakeClock::Virtual(clock.clone())).unwrap();
+        let stream = characteristic.start_notify().unwrap();
+        clock.advance(INTERVAL * 5);
+        assert_eq!(pending(&stream), vec![vec![1], vec![2]]);
+        clock.advance(INTERVAL * 5);
+        assert!(pending(&stream).is_empty());
+        assert_eq!(characteristic.get_value(), Ok(vec![2]));
+    }
+
+    #[test]
+    fn nothing_is_generated_while_not_notifying() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let clock = FakeVirtualClock::new();
+        let generator = FakeValueGenerator::Counter { start: 10, step: 1 };
+        characteristic.set_generator(generator, INTERVAL, FakeClock::Virtual(clock.clone())).unwrap();
+        clock.advance(INTERVAL * 3);
+        assert_eq!(characteristic.get_value(), Ok(vec![1, 2, 3]));
+        let stream = characteristic.start_notify().unwrap();
+        clock.advance(INTERVAL);
+        assert_eq!(pending(&stream), vec![vec![10]]);
+        assert_eq!(clock.now(), INTERVAL * 4);
+    }
+
+    #[test]
+    fn set_generator_cancels_the_previous_one() {
+        let (_adapter, _device, characteristic) = connected_characteristic(&["notify"]);
+        let clock = FakeVirtualClock::new();
+        let first = FakeValueGenerator::Counter { start: 0, step: 1 };
+        characteristic.set_generator(first, INTERVAL, FakeClock::Virtual(clock.clone())).unwrap();
+        let second = FakeValueGenerator::Counter { start: 100, step: 1 };
+        characteristic.set_generator(second, INTERVAL, FakeClock::Virtual(clock.clone())).unwrap();
+        let stream = characteristic.start_notify().unwrap();
+        clock.advance(INTERVAL * 2);
+        assert_eq!(pending(&stream), vec![vec![100], vec![101]]);
+        characteristic.clear_generator().unwrap();
+        clock.advance(INTERVAL * 2);
+        assert!(pending(&stream).is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use fake_error::FakeBluetoothError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+
+// Peripheral-side callbacks for GATT reads and writes. They get the offset
+// of the request and answer with the data, or with the ATT error code the
+// peripheral would respond with.
+type FakeReadHandler = dyn FnMut(u16) -> Result<Vec<u8>, u8> + Send;
+
+type FakeWriteHandler = dyn FnMut(u16, Vec<u8>) -> Result<(), u8> + Send;
+
+// A handler stays in its slot while it runs, so one that is cleared or
+// replaced meanwhile stays cleared or replaced. Calls from other threads
+// wait their turn; `caller` lets a nested call from the handler itself,
+// e.g. one that reads its own attribute, tell it would deadlock.
+struct FakeHandler<H: ?Sized> {
+    caller: Mutex<Option<ThreadId>>,
+    handler: Mutex<Box<H>>,
+}
+
+impl<H: ?Sized> FakeHandler<H> {
+    fn new(handler: Box<H>) -> Arc<FakeHandler<H>> {
+        Arc::new(FakeHandler {
+            caller: Mutex::new(None),
+            handler: Mutex::new(handler),
+        })
+    }
+
+    // None for a nested call, which is left to the stored value.
+    fn call<R, F: FnOnce(&mut H) -> R>(&self, call: F) -> Option<R> {
+        let current = thread::current().id();
+        if *::lock(&self.caller) == Some(current) {
+            return None;
+        }
+        let mut handler = ::lock(&self.handler);
+        *::lock(&self.caller) = Some(current);
+        let result = call(&mut handler);
+        *::lock(&self.caller) = None;
+        Some(result)
+    }
+}
+
+impl<H: ?Sized> fmt::Debug for FakeHandler<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FakeHandler(..)")
+    }
+}
+
+type FakeHandlerSlot<H> = Mutex<Option<Arc<FakeHandler<H>>>>;
+
+// The stored value a characteristic or descriptor falls back on without a
+// handler.
+pub(crate) trait FakeAttributeValue {
+    fn get_value(&self) -> Result<Vec<u8>, FakeBluetoothError>;
+
+    fn set_value(&self, value: Vec<u8>) -> Result<(), FakeBluetoothError>;
+
+    fn get_max_length(&self) -> Result<usize, FakeBluetoothError>;
+}
+
+// The handlers of a characteristic or descriptor.
+#[derive(Debug, Default)]
+pub(crate) struct FakeAttributeHandlers {
+    read_handler: FakeHandlerSlot<FakeReadHandler>,
+    write_handler: FakeHandlerSlot<FakeWriteHandler>,
+}
+
+impl FakeAttributeHandlers {
+    // The handler answers every read in place of the stored value, and what
+    // it returns for offset 0 becomes the stored value.
+    pub(crate) fn set_read_handler<F>(&self, handler: F)
+        where F: FnMut(u16) -> Result<Vec<u8>, u8> + Send + 'static
+    {
+        *::lock(&self.read_handler) = Some(FakeHandler::new(Box::new(handler)));
+    }
+
+    pub(crate) fn clear_read_handler(&self) {
+        *::lock(&self.read_handler) = None;
+    }
+
+    // The handler takes every write instead of the stored value, which is
+    // left for the handler to update if it wants to.
+    pub(crate) fn set_write_handler<F>(&self, handler: F)
+        where F: FnMut(u16, Vec<u8>) -> Result<(), u8> + Send + 'static
+    {
+        *::lock(&self.write_handler) = Some(FakeHandler::new(Box::new(handler)));
+    }
+
+    pub(crate) fn clear_write_handler(&self) {
+        *::lock(&self.write_handler) = None;
+    }
+
+    pub(crate) fn has_write_handler(&self) -> bool {
+        ::lock(&self.write_handler).is_some()
+    }
+
+    // Whatever the handler answers past the attribute's maximum length is
+    // cut off. `check_access` runs again once the handler has answered, as
+    // the link may have gone away meanwhile.
+    pub(crate) fn handle_read<A, C>(&self, attribute: &A, offset: u16, check_access: C) -> Result<Vec<u8>, FakeBluetoothError>
+        where A: FakeAttributeValue,
+              C: Fn() -> Result<(), FakeBluetoothError>
+    {
+        let max_length = attribute.get_max_length()?;
+        let handler = ::lock(&self.read_handler).clone();
+        let result = match handler.and_then(|handler| handler.call(|handler| handler(offset))) {
+            Some(result) => result,
+            None => return ::read_at(&attribute.get_value()?, offset, max_length),
+        };
+        check_access()?;
+        let mut value = result.map_err(FakeBluetoothError::AttError)?;
+        value.truncate(max_length.saturating_sub(offset as usize));
+        if offset == 0 {
+            attribute.set_value(value.clone())?;
+        }
+        Ok(value)
+    }
+
+    pub(crate) fn handle_write<A, C>(&self, attribute: &A, offset: u16, value: Vec<u8>, check_access: C) -> Result<(), FakeBluetoothError>
+        where A: FakeAttributeValue,
+              C: Fn() -> Result<(), FakeBluetoothError>
+    {
+        let max_length = attribute.get_max_length()?;
+        ::check_length(offset, &value, max_length)?;
+        let handler = ::lock(&self.write_handler).clone();
+        let result = match handler.and_then(|handler| handler.call(|handler| handler(offset, value.clone()))) {
+            Some(result) => result,
+            None => return attribute.set_value(::write_at(&attribute.get_value()?, offset, &value, max_length)?),
+        };
+        check_access()?;
+        result.map_err(FakeBluetoothError::AttError)
+    }
+}
@@ -0,0 +1,62 @@
+// The options BlueZ's ReadValue and WriteValue take as a dictionary.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FakeReadOptions {
+    offset: u16,
+}
+
+impl FakeReadOptions {
+    pub fn new() -> FakeReadOptions {
+        FakeReadOptions::default()
+    }
+
+    pub fn offset(mut self, offset: u16) -> FakeReadOptions {
+        self.offset = offset;
+        self
+    }
+
+    pub fn get_offset(&self) -> u16 {
+        self.offset
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FakeWriteType {
+    // Write without response.
+    Command,
+    // Write with response.
+    Request,
+    // A prepared write, executed right away.
+    Reliable,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FakeWriteOptions {
+    offset: u16,
+    write_type: Option<FakeWriteType>,
+}
+
+impl FakeWriteOptions {
+    pub fn new() -> FakeWriteOptions {
+        FakeWriteOptions::default()
+    }
+
+    pub fn offset(mut self, offset: u16) -> FakeWriteOptions {
+        self.offset = offset;
+        self
+    }
+
+    // Without a type, like BlueZ, a request is made if the flags allow one
+    // and a command otherwise.
+    pub fn write_type(mut self, write_type: FakeWriteType) -> FakeWriteOptions {
+        self.write_type = Some(write_type);
+        self
+    }
+
+    pub fn get_offset(&self) -> u16 {
+        self.offset
+    }
+
+    pub fn get_write_type(&self) -> Option<FakeWriteType> {
+        self.write_type
+    }
+}
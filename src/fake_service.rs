@@ -1,92 +1,216 @@
+use fake_adapter::{FakeBluetoothAdapter, FakeBluetoothObject};
 use fake_characteristic::FakeBluetoothGATTCharacteristic;
 use fake_device::FakeBluetoothDevice;
-use std::error::Error;
-use std::sync::Arc;
+use fake_error::{FakeBluetoothBuildError, FakeBluetoothError};
+use fake_event::{FakeBluetoothEvent, FakePropertyValue};
+use std::sync::{Arc, Mutex, Weak};
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct FakeBluetoothGATTService {
-    object_path: String,
-    device: Arc<FakeBluetoothDevice>,
-    gatt_characteristics: Vec<Arc<FakeBluetoothGATTCharacteristic>>,
-    is_primary: bool,
-    included_services: Vec<Arc<FakeBluetoothGATTService>>,
-    uuid: String,
+    object_path: Mutex<String>,
+    device: Mutex<Weak<FakeBluetoothDevice>>,
+    gatt_characteristics: Mutex<Vec<Arc<FakeBluetoothGATTCharacteristic>>>,
+    is_primary: Mutex<bool>,
+    included_services: Mutex<Vec<Arc<FakeBluetoothGATTService>>>,
+    uuid: Mutex<String>,
+    is_removed: Mutex<bool>,
 }
 
 impl FakeBluetoothGATTService {
-    pub fn new(object_path: String,
-               device: Arc<FakeBluetoothDevice>,
-               gatt_characteristics: Vec<Arc<FakeBluetoothGATTCharacteristic>>,
-               is_primary: bool,
-               included_services: Vec<Arc<FakeBluetoothGATTService>>,
-               uuid: String)
-               -> FakeBluetoothGATTService {
+    pub fn new_empty() -> FakeBluetoothGATTService {
         FakeBluetoothGATTService {
-            object_path: object_path,
-            device: device,
-            gatt_characteristics: gatt_characteristics,
-            is_primary: is_primary,
-            included_services: included_services,
-            uuid: uuid,
+            object_path: Mutex::new(String::new()),
+            device: Mutex::new(Weak::new()),
+            gatt_characteristics: Mutex::new(vec![]),
+            is_primary: Mutex::new(false),
+            included_services: Mutex::new(vec![]),
+            uuid: Mutex::new(String::new()),
+            is_removed: Mutex::new(false),
         }
     }
 
-    pub fn new_empty() -> FakeBluetoothGATTService {
-        FakeBluetoothGATTService {
-            object_path: String::new(),
-            device: Arc::new(FakeBluetoothDevice::new_empty()),
-            gatt_characteristics: vec![],
-            is_primary: false,
-            included_services: vec![],
-            uuid: String::new(),
+    pub fn get_id(&self) -> String {
+        ::lock(&self.object_path).clone()
+    }
+
+    pub fn set_id(&self, path: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let old_path = ::replace(&self.object_path, path.clone());
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.rename_object(&old_path, path);
         }
+        Ok(())
     }
 
-    pub fn get_id(&self) -> String {
-        self.object_path.clone()
+    pub fn get_device(&self) -> Result<Arc<FakeBluetoothDevice>, FakeBluetoothError> {
+        self.check_valid()?;
+        match ::lock(&self.device).upgrade() {
+            Some(device) => Ok(device),
+            None => Err(FakeBluetoothError::NotFound(String::from("No device found."))),
+        }
     }
 
-    pub fn set_id(&mut self, path: String) {
-        self.object_path = path;
+    pub(crate) fn check_unattached(&self) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        if ::lock(&self.device).upgrade().is_some() {
+            return Err(FakeBluetoothError::AlreadyExists(format!("Service {} already belongs to a device.", self.get_id())));
+        }
+        Ok(())
+    }
+
+    pub fn set_device(&self, device: &Arc<FakeBluetoothDevice>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        *::lock(&self.device) = Arc::downgrade(device);
+        Ok(())
+    }
+
+    make_getter!(get_gatt_characteristics, gatt_characteristics, Vec<Arc<FakeBluetoothGATTCharacteristic>>);
+
+    // Works like remove_characteristic() and add_characteristic() for
+    // whatever changed, once every new characteristic is known to be addable.
+    pub fn set_gatt_characteristics(self: &Arc<Self>,
+                                    characteristics: Vec<Arc<FakeBluetoothGATTCharacteristic>>)
+                                    -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let (removed, added) = ::diff_children(&::lock(&self.gatt_characteristics), &characteristics)?;
+        added.iter().try_for_each(|characteristic| characteristic.check_unattached())?;
+        for characteristic in removed {
+            self.remove_characteristic(characteristic.get_id())?;
+        }
+        for characteristic in added {
+            self.add_characteristic(characteristic)?;
+        }
+        *::lock(&self.gatt_characteristics) = characteristics;
+        Ok(())
+    }
+
+    make_getter!(is_primary, is_primary, bool);
+
+    make_setter!(set_is_primary, is_primary, bool, "Primary");
+
+    make_getter!(get_includes, included_services, Vec<Arc<FakeBluetoothGATTService>>);
+
+    make_setter!(set_includes, included_services, Vec<Arc<FakeBluetoothGATTService>>);
+
+    make_getter!(get_uuid, uuid, String);
+
+    make_setter!(set_uuid, uuid, String, "UUID", ::parse_uuid);
+
+    pub fn add_characteristic(self: &Arc<Self>, characteristic: Arc<FakeBluetoothGATTCharacteristic>) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        characteristic.check_unattached()?;
+        characteristic.set_service(self)?;
+        let position = ::lock(&self.gatt_characteristics).len() as u16 + 1;
+        characteristic.update_paths(&self.get_id(), self.get_device().ok().as_deref(), position);
+        ::lock(&self.gatt_characteristics).push(characteristic.clone());
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.replace_objects(&self.get_id(), vec![], characteristic.get_objects());
+        }
+        Ok(())
     }
 
-    pub fn get_device(&self) -> Result<Arc<FakeBluetoothDevice>, Box<Error>> {
-        Ok(self.device.clone())
+    pub fn remove_characteristic(&self, id: String) -> Result<(), FakeBluetoothError> {
+        self.check_valid()?;
+        let characteristic = {
+            let mut characteristics = ::lock(&self.gatt_characteristics);
+            match characteristics.iter().position(|characteristic| characteristic.get_id() == id) {
+                Some(index) => characteristics.remove(index),
+                None => return Err(FakeBluetoothError::NotFound(format!("No characteristic found with id: {}.", id))),
+            }
+        };
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.replace_objects(&self.get_id(), characteristic.get_objects(), vec![]);
+        }
+        characteristic.invalidate();
+        Ok(())
     }
 
-    pub fn set_device(&mut self, device: Arc<FakeBluetoothDevice>) {
-        self.device = device;
+    // Services are numbered by the attribute handle the device hands out;
+    // without a device the position among its siblings is used instead.
+    pub(crate) fn update_paths(&self, device_path: &str, device: Option<&FakeBluetoothDevice>, position: u16) {
+        let handle = device.map_or(position, |device| device.allocate_handle());
+        let path = format!("{}/service{:04x}", device_path, handle);
+        *::lock(&self.object_path) = path.clone();
+        for (index, characteristic) in ::lock(&self.gatt_characteristics).iter().enumerate() {
+            characteristic.update_paths(&path, device, index as u16 + 1);
+        }
     }
 
-    pub fn get_gatt_characteristics(&self) -> Result<Vec<Arc<FakeBluetoothGATTCharacteristic>>, Box<Error>> {
-        Ok(self.gatt_characteristics.clone())
+    pub(crate) fn invalidate(&self) {
+        *::lock(&self.is_removed) = true;
+        *::lock(&self.device) = Weak::new();
+        for characteristic in ::lock(&self.gatt_characteristics).iter() {
+            characteristic.invalidate();
+        }
     }
 
-    pub fn set_gatt_characteristics(&mut self, characteristics: Vec<Arc<FakeBluetoothGATTCharacteristic>>) {
-        self.gatt_characteristics = characteristics;
+    fn check_valid(&self) -> Result<(), FakeBluetoothError> {
+        if *::lock(&self.is_removed) {
+            return Err(FakeBluetoothError::NotFound(format!("The service {} has been removed.", self.get_id())));
+        }
+        Ok(())
     }
 
-    pub fn is_primary(&self) -> Result<bool, Box<Error>> {
-        Ok(self.is_primary)
+    fn property_changed(&self, property: &str, old_value: FakePropertyValue, new_value: FakePropertyValue) {
+        if let Ok(adapter) = self.get_adapter() {
+            adapter.emit(FakeBluetoothEvent::PropertyChanged {
+                object_path: self.get_id(),
+                property: String::from(property),
+                old_value,
+                new_value,
+            });
+        }
     }
 
-    pub fn set_is_primary(&mut self, value: bool) {
-        self.is_primary = value;
+    pub(crate) fn get_adapter(&self) -> Result<Arc<FakeBluetoothAdapter>, FakeBluetoothError> {
+        self.get_device()?.get_adapter()
     }
 
-    pub fn get_includes(&self) -> Result<Vec<Arc<FakeBluetoothGATTService>>, Box<Error>> {
-        Ok(self.included_services.clone())
+    pub(crate) fn get_objects(self: &Arc<Self>) -> Vec<(String, FakeBluetoothObject)> {
+        let mut objects = vec![(self.get_id(), FakeBluetoothObject::Service(self.clone()))];
+        for characteristic in ::lock(&self.gatt_characteristics).iter() {
+            objects.extend(characteristic.get_objects());
+        }
+        objects
     }
+}
 
-    pub fn set_includes(&mut self, included_services: Vec<Arc<FakeBluetoothGATTService>>) {
-        self.included_services = included_services;
+#[derive(Clone, Debug)]
+pub struct FakeBluetoothGATTServiceBuilder {
+    uuid: String,
+    is_primary: bool,
+}
+
+impl Default for FakeBluetoothGATTServiceBuilder {
+    fn default() -> FakeBluetoothGATTServiceBuilder {
+        FakeBluetoothGATTServiceBuilder {
+            uuid: String::new(),
+            is_primary: true,
+        }
     }
+}
 
-    pub fn get_uuid(&self) -> Result<String, Box<Error>> {
-        Ok(self.uuid.clone())
+impl FakeBluetoothGATTServiceBuilder {
+    pub fn new() -> FakeBluetoothGATTServiceBuilder {
+        FakeBluetoothGATTServiceBuilder::default()
     }
 
-    pub fn set_uuid(&mut self, uuid: String) {
+    pub fn uuid(mut self, uuid: String) -> FakeBluetoothGATTServiceBuilder {
         self.uuid = uuid;
+        self
+    }
+
+    pub fn primary(mut self, value: bool) -> FakeBluetoothGATTServiceBuilder {
+        self.is_primary = value;
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<FakeBluetoothGATTService>, FakeBluetoothBuildError> {
+        let uuid = ::parse_uuid(&self.uuid)?;
+        Ok(Arc::new(FakeBluetoothGATTService {
+            uuid: Mutex::new(uuid),
+            is_primary: Mutex::new(self.is_primary),
+            ..FakeBluetoothGATTService::new_empty()
+        }))
     }
 }
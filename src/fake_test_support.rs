@@ -0,0 +1,44 @@
+use fake_adapter::{FakeBluetoothAdapter, FakeBluetoothAdapterBuilder};
+use fake_characteristic::{FakeBluetoothGATTCharacteristic, FakeBluetoothGATTCharacteristicBuilder, FakeNotificationStream};
+use fake_device::{FakeBluetoothDevice, FakeBluetoothDeviceBuilder};
+use fake_service::FakeBluetoothGATTServiceBuilder;
+use std::sync::Arc;
+
+// A powered adapter with one connected device that offers one service.
+pub(crate) fn connected_device() -> (Arc<FakeBluetoothAdapter>, Arc<FakeBluetoothDevice>) {
+    let adapter = FakeBluetoothAdapterBuilder::new().build().unwrap();
+    let device = FakeBluetoothDeviceBuilder::new().address(String::from("00:11:22:33:44:55")).build().unwrap();
+    adapter.add_device(device.clone()).unwrap();
+    let service = FakeBluetoothGATTServiceBuilder::new().uuid(String::from("180d")).build().unwrap();
+    device.add_service(service).unwrap();
+    device.connect().unwrap();
+    (adapter, device)
+}
+
+// Adds a characteristic holding [1, 2, 3] to the device's first service.
+pub(crate) fn add_characteristic(device: &FakeBluetoothDevice, flags: &[&str]) -> Arc<FakeBluetoothGATTCharacteristic> {
+    let characteristic = FakeBluetoothGATTCharacteristicBuilder::new()
+        .uuid(String::from("2a37"))
+        .flags(flags.iter().map(|flag| String::from(*flag)).collect())
+        .value(vec![1, 2, 3])
+        .build()
+        .unwrap();
+    device.get_gatt_services().unwrap()[0].add_characteristic(characteristic.clone()).unwrap();
+    characteristic
+}
+
+pub(crate) fn connected_characteristic(flags: &[&str])
+                                       -> (Arc<FakeBluetoothAdapter>, Arc<FakeBluetoothDevice>, Arc<FakeBluetoothGATTCharacteristic>) {
+    let (adapter, device) = connected_device();
+    let characteristic = add_characteristic(&device, flags);
+    (adapter, device, characteristic)
+}
+
+// Whatever the stream has received so far, without waiting for more.
+pub(crate) fn pending(stream: &FakeNotificationStream) -> Vec<Vec<u8>> {
+    let mut values = vec![];
+    while let Some(value) = stream.try_next_value() {
+        values.push(value);
+    }
+    values
+}
@@ -1,9 +1,230 @@
-extern crate rustc_serialize;
 extern crate core;
 
+use fake_error::{ATT_ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH, ATT_ERROR_INVALID_OFFSET, FakeBluetoothBuildError, FakeBluetoothError};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+macro_rules! make_getter {
+    ($function_name:ident, $attr:ident, $ret_type:ty) => {
+        pub fn $function_name(&self) -> Result<$ret_type, FakeBluetoothError> {
+            self.check_valid()?;
+            Ok(::lock(&self.$attr).clone())
+        }
+    };
+}
+
+macro_rules! make_setter {
+    ($function_name:ident, $attr:ident, $value_type:ty) => {
+        pub fn $function_name(&self, value: $value_type) -> Result<(), FakeBluetoothError> {
+            self.check_valid()?;
+            *::lock(&self.$attr) = value;
+            Ok(())
+        }
+    };
+    ($function_name:ident, $attr:ident, $value_type:ty, $property:expr) => {
+        pub fn $function_name(&self, value: $value_type) -> Result<(), FakeBluetoothError> {
+            self.check_valid()?;
+            if let Some(old_value) = ::update(&self.$attr, value.clone()) {
+                self.property_changed($property, old_value.into(), value.into());
+            }
+            Ok(())
+        }
+    };
+    // Runs the value through the same check the builder uses first.
+    ($function_name:ident, $attr:ident, $value_type:ty, $property:expr, $parse:path) => {
+        pub fn $function_name(&self, value: $value_type) -> Result<(), FakeBluetoothError> {
+            self.check_valid()?;
+            let value: $value_type = $parse(&value)?;
+            if let Some(old_value) = ::update(&self.$attr, value.clone()) {
+                self.property_changed($property, old_value.into(), value.into());
+            }
+            Ok(())
+        }
+    };
+}
+
+// The public side of an attribute's FakeAttributeHandlers.
+macro_rules! make_handler_setters {
+    () => {
+        pub fn set_read_handler<F>(&self, handler: F) -> Result<(), FakeBluetoothError>
+            where F: FnMut(u16) -> Result<Vec<u8>, u8> + Send + 'static
+        {
+            self.check_valid()?;
+            self.handlers.set_read_handler(handler);
+            Ok(())
+        }
+
+        pub fn clear_read_handler(&self) -> Result<(), FakeBluetoothError> {
+            self.check_valid()?;
+            self.handlers.clear_read_handler();
+            Ok(())
+        }
+
+        pub fn set_write_handler<F>(&self, handler: F) -> Result<(), FakeBluetoothError>
+            where F: FnMut(u16, Vec<u8>) -> Result<(), u8> + Send + 'static
+        {
+            self.check_valid()?;
+            self.handlers.set_write_handler(handler);
+            Ok(())
+        }
+
+        pub fn clear_write_handler(&self) -> Result<(), FakeBluetoothError> {
+            self.check_valid()?;
+            self.handlers.clear_write_handler();
+            Ok(())
+        }
+    };
+}
+
 pub mod fake_adapter;
+pub mod fake_agent;
 pub mod fake_device;
 pub mod fake_service;
 pub mod fake_characteristic;
 pub mod fake_descriptor;
 pub mod fake_discovery_session;
+pub mod fake_error;
+pub mod fake_event;
+pub mod fake_generator;
+mod fake_handler;
+pub mod fake_options;
+#[cfg(test)]
+mod fake_test_support;
+pub mod modalias;
+
+// A panic while a value was locked leaves the value itself intact, so a
+// poisoned lock is recovered instead of poisoning every later call.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn replace<T>(mutex: &Mutex<T>, value: T) -> T {
+    mem::replace(&mut *lock(mutex), value)
+}
+
+// Stores `value`, handing back the old one only if it actually changed.
+fn update<T: PartialEq>(mutex: &Mutex<T>, value: T) -> Option<T> {
+    let mut current = lock(mutex);
+    if *current == value {
+        return None;
+    }
+    Some(mem::replace(&mut *current, value))
+}
+
+const BLUETOOTH_BASE_UUID: &str = "-0000-1000-8000-00805f9b34fb";
+
+const MAX_ATTRIBUTE_LENGTH: usize = 512;
+
+const DEFAULT_MTU: u16 = 23;
+
+const MAX_MTU: u16 = 517;
+
+const CHARACTERISTIC_FLAGS: &[&str] = &["broadcast", "read", "write-without-response", "write", "notify",
+                                        "indicate", "authenticated-signed-writes", "extended-properties",
+                                        "reliable-write", "writable-auxiliaries", "encrypt-read", "encrypt-write",
+                                        "encrypt-authenticated-read", "encrypt-authenticated-write",
+                                        "secure-read", "secure-write", "authorize"];
+
+const DESCRIPTOR_FLAGS: &[&str] = &["read", "write", "encrypt-read", "encrypt-write", "encrypt-authenticated-read",
+                                    "encrypt-authenticated-write", "secure-read", "secure-write", "authorize"];
+
+// What a read at `offset` returns of `value`, which is cut to the maximum
+// attribute length first.
+fn read_at(value: &[u8], offset: u16, max_length: usize) -> Result<Vec<u8>, FakeBluetoothError> {
+    let offset = offset as usize;
+    if offset > value.len() {
+        return Err(FakeBluetoothError::AttError(ATT_ERROR_INVALID_OFFSET));
+    }
+    Ok(value.iter().take(max_length).skip(offset).cloned().collect())
+}
+
+// The value after writing `data` at `offset`; the write ends the value.
+fn write_at(value: &[u8], offset: u16, data: &[u8], max_length: usize) -> Result<Vec<u8>, FakeBluetoothError> {
+    check_length(offset, data, max_length)?;
+    let offset = offset as usize;
+    if offset > value.len() {
+        return Err(FakeBluetoothError::AttError(ATT_ERROR_INVALID_OFFSET));
+    }
+    Ok(value[..offset].iter().chain(data).cloned().collect())
+}
+
+fn check_length(offset: u16, data: &[u8], max_length: usize) -> Result<(), FakeBluetoothError> {
+    if offset as usize + data.len() > max_length {
+        return Err(FakeBluetoothError::AttError(ATT_ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH));
+    }
+    Ok(())
+}
+
+// The children to remove and the children to add.
+type ChildrenDiff<T> = (Vec<Arc<T>>, Vec<Arc<T>>);
+
+// Splits a new list of children against the current one, refusing a child
+// that is listed twice.
+fn diff_children<T>(old: &[Arc<T>], new: &[Arc<T>]) -> Result<ChildrenDiff<T>, FakeBluetoothError> {
+    for (index, child) in new.iter().enumerate() {
+        if new[..index].iter().any(|other| Arc::ptr_eq(other, child)) {
+            return Err(FakeBluetoothError::AlreadyExists(String::from("The same object is listed twice.")));
+        }
+    }
+    let removed = old.iter().filter(|child| !new.iter().any(|other| Arc::ptr_eq(other, child))).cloned().collect();
+    let added = new.iter().filter(|child| !old.iter().any(|other| Arc::ptr_eq(other, child))).cloned().collect();
+    Ok((removed, added))
+}
+
+// Addresses are six colon separated hex octets, stored upper case like BlueZ reports them.
+fn parse_address(address: &str) -> Result<String, FakeBluetoothBuildError> {
+    let octets: Vec<&str> = address.split(':').collect();
+    if octets.len() != 6 || !octets.iter().all(|octet| octet.len() == 2 && is_hex(octet)) {
+        return Err(FakeBluetoothBuildError::InvalidAddress(address.to_owned()));
+    }
+    Ok(address.to_uppercase())
+}
+
+// Accepts full 128-bit UUIDs as well as 16 and 32-bit short forms, which are
+// expanded with the Bluetooth base UUID.
+fn parse_uuid(uuid: &str) -> Result<String, FakeBluetoothBuildError> {
+    let full = match uuid.len() {
+        4 => format!("0000{}{}", uuid, BLUETOOTH_BASE_UUID),
+        8 => format!("{}{}", uuid, BLUETOOTH_BASE_UUID),
+        _ => uuid.to_owned(),
+    };
+    let groups: Vec<&str> = full.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    if lengths != [8, 4, 4, 4, 12] || !groups.iter().all(|group| is_hex(group)) {
+        return Err(FakeBluetoothBuildError::InvalidUuid(uuid.to_owned()));
+    }
+    Ok(full.to_lowercase())
+}
+
+fn parse_uuids(uuids: &[String]) -> Result<Vec<String>, FakeBluetoothBuildError> {
+    uuids.iter().map(|uuid| parse_uuid(uuid)).collect()
+}
+
+fn check_flags(flags: &[String], allowed: &[&str]) -> Result<(), FakeBluetoothBuildError> {
+    match flags.iter().find(|flag| !allowed.contains(&flag.as_str())) {
+        Some(flag) => Err(FakeBluetoothBuildError::InvalidFlag(flag.clone())),
+        None => Ok(()),
+    }
+}
+
+fn parse_characteristic_flags(flags: &[String]) -> Result<Vec<String>, FakeBluetoothBuildError> {
+    check_flags(flags, CHARACTERISTIC_FLAGS)?;
+    Ok(flags.to_vec())
+}
+
+fn parse_descriptor_flags(flags: &[String]) -> Result<Vec<String>, FakeBluetoothBuildError> {
+    check_flags(flags, DESCRIPTOR_FLAGS)?;
+    Ok(flags.to_vec())
+}
+
+// An empty modalias stands for none at all.
+fn parse_modalias(modalias: &str) -> Result<String, FakeBluetoothBuildError> {
+    if !modalias.is_empty() && modalias.parse::<modalias::Modalias>().is_err() {
+        return Err(FakeBluetoothBuildError::InvalidModalias(modalias.to_owned()));
+    }
+    Ok(modalias.to_owned())
+}
+
+fn is_hex(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_hexdigit())
+}
@@ -0,0 +1,147 @@
+use fake_error::FakeBluetoothError;
+use std::fmt;
+use std::str::FromStr;
+
+// A device id in the form BlueZ exposes it, e.g. `usb:v1D6Bp0246d0525`.
+// Any of the vendor, product and device fields may be missing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Modalias {
+    source: String,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    device: Option<u16>,
+}
+
+impl Modalias {
+    pub fn new(source: String, vendor: Option<u16>, product: Option<u16>, device: Option<u16>) -> Modalias {
+        Modalias {
+            source: source.to_lowercase(),
+            vendor,
+            product,
+            device,
+        }
+    }
+
+    pub fn get_source(&self) -> String {
+        self.source.clone()
+    }
+
+    pub fn get_vendor(&self) -> Option<u16> {
+        self.vendor
+    }
+
+    pub fn get_product(&self) -> Option<u16> {
+        self.product
+    }
+
+    pub fn get_device(&self) -> Option<u16> {
+        self.device
+    }
+}
+
+impl FromStr for Modalias {
+    type Err = FakeBluetoothError;
+
+    fn from_str(modalias: &str) -> Result<Modalias, FakeBluetoothError> {
+        let invalid = || FakeBluetoothError::InvalidArgument(format!("Invalid modalias: {:?}.", modalias));
+        let (source, mut ids) = match modalias.find(':') {
+            Some(index) => (&modalias[..index], &modalias[index + 1..]),
+            None => return Err(invalid()),
+        };
+        if source.is_empty() || !source.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid());
+        }
+
+        let mut fields = [None; 3];
+        let mut next_field = 0;
+        while !ids.is_empty() {
+            let field = match ids.as_bytes()[0].to_ascii_lowercase() {
+                b'v' => 0,
+                b'p' => 1,
+                b'd' => 2,
+                _ => return Err(invalid()),
+            };
+            // The fields are optional, but always come in vendor, product, device order.
+            if field < next_field || ids.len() < 5 || !ids.is_char_boundary(5) || !::is_hex(&ids[1..5]) {
+                return Err(invalid());
+            }
+            fields[field] = Some(u16::from_str_radix(&ids[1..5], 16).map_err(|_| invalid())?);
+            next_field = field + 1;
+            ids = &ids[5..];
+        }
+
+        Ok(Modalias::new(String::from(source), fields[0], fields[1], fields[2]))
+    }
+}
+
+impl fmt::Display for Modalias {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.source)?;
+        for &(tag, value) in &[('v', self.vendor), ('p', self.product), ('d', self.device)] {
+            if let Some(value) = value {
+                write!(f, "{}{:04X}", tag, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Modalias;
+
+    fn parse(modalias: &str) -> Option<Modalias> {
+        modalias.parse().ok()
+    }
+
+    #[test]
+    fn parses_all_fields() {
+        let modalias = parse("usb:v1D6Bp0246d0525").unwrap();
+        assert_eq!(modalias.get_source(), "usb");
+        assert_eq!(modalias.get_vendor(), Some(0x1d6b));
+        assert_eq!(modalias.get_product(), Some(0x0246));
+        assert_eq!(modalias.get_device(), Some(0x0525));
+    }
+
+    #[test]
+    fn ignores_case() {
+        assert_eq!(parse("USB:V1d6bP0246D0525"), parse("usb:v1D6Bp0246d0525"));
+    }
+
+    #[test]
+    fn allows_missing_fields() {
+        assert_eq!(parse("bluetooth:"), Some(Modalias::new(String::from("bluetooth"), None, None, None)));
+        assert_eq!(parse("usb:p0246"), Some(Modalias::new(String::from("usb"), None, Some(0x0246), None)));
+        assert_eq!(parse("usb:v1D6Bd0525"), Some(Modalias::new(String::from("usb"), Some(0x1d6b), None, Some(0x0525))));
+    }
+
+    #[test]
+    fn rejects_out_of_order_or_repeated_fields() {
+        assert_eq!(parse("usb:p0246v1D6B"), None);
+        assert_eq!(parse("usb:d0525p0246"), None);
+        assert_eq!(parse("usb:v1D6Bv1D6B"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for modalias in &["", "usb", ":v1D6B", "us b:v1D6B", "usb:x1D6B", "usb:v1D6", "usb:v1D6Bp", "usb:vG000",
+                          "usb:v+1D6", "usb:v1D6B "] {
+            assert_eq!(parse(modalias), None, "{:?}", modalias);
+        }
+    }
+
+    #[test]
+    fn rejects_non_ascii_input() {
+        for modalias in &["üsb:v1D6B", "usb:é1D6B", "usb:v1Dé", "usb:v1D6Bp02é"] {
+            assert_eq!(parse(modalias), None, "{:?}", modalias);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for modalias in &["usb:v1D6Bp0246d0525", "bluetooth:v000Fp1200", "usb:d0001", "bluetooth:"] {
+            assert_eq!(parse(modalias).unwrap().to_string(), *modalias);
+        }
+        assert_eq!(parse("USB:v1d6b").unwrap().to_string(), "usb:v1D6B");
+    }
+}